@@ -7,14 +7,39 @@ use core::arch::{asm, global_asm};
 use core::ptr::with_exposed_provenance_mut;
 use core::time::Duration;
 use crab_pi::{println, uart, watchdog};
-use constants::{BOOT_OP, UART_BAUD_RATE};
+use boot_proto::{BOOT_ERR, BOOT_OP, DecodeError, Decoder, Frame};
+use constants::{BOOT_CHUNK_SIZE, UART_BAUD_RATE};
 use crab_pi::cycle_count::cycle_cnt_init;
+use crab_pi::mailbox::mbox_get_memory;
 use crab_pi::memory::gcc_mb;
 use crab_pi::timer::sleep;
-use crab_pi::uart::{can_read_timeout, read_bytes, write_bytes};
+use crab_pi::uart::{can_read_timeout, read_bytes_timeout, write_bytes};
 
 global_asm!(include_str!("../asm/boot.S"));
 
+// How long the line may stay quiet in the middle of a chunk before we give up on it.
+const CHUNK_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+// How long PUT_PROG_INFO may take once the unix side starts answering.
+const PROG_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+// Chunk timeouts in a row before we decide the unix side is gone.
+const MAX_IDLE_NAKS: u32 = 30;
+// Where our copy may start, so its sections stay as aligned as the linker left them.
+const RELOCATION_ALIGN: u32 = 0x1000;
+
+// Why a boot attempt failed. Sent back as [BOOT_ERROR, code, detail] before we
+// start over with GET_PROG_INFO.
+#[derive(Copy, Clone)]
+struct Failure {
+    code: BOOT_ERR,
+    detail: u32,
+}
+
+impl Failure {
+    const fn new(code: BOOT_ERR, detail: u32) -> Self {
+        Self { code, detail }
+    }
+}
+
 unsafe extern "C" {
     // We declare these as `[u32; 0]` so that they have an alignment of 4 but a size of zero. This
     // is to prevent aliasing, since otherwise producing mutable references to anything in the BSS
@@ -23,9 +48,31 @@ unsafe extern "C" {
     static __bss_start__: [u32; 0];
     static __bss_end__: [u32; 0];
 
+    // Our copy and the stack above it: sent code must stay out of here.
+    static __code_start__: [u32; 0];
+    static __stack_init__: [u32; 0];
+
     fn BRANCHTO(addr: u32) -> !;
 }
 
+// Where code may not go: [start, end) of the relocated bootloader and its stack.
+fn bootloader_range() -> (u32, u32) {
+    unsafe {
+        (
+            &__code_start__ as *const u32 as u32,
+            &__stack_init__ as *const u32 as u32,
+        )
+    }
+}
+
+// Where <boot.S> copies us: as high as DRAM goes, which the mailbox tells us, with our stack
+// at the top. Runs before the copy, on a stack below 0x8000, with .bss not yet zeroed.
+#[unsafe(no_mangle)]
+extern "C" fn relocation_base() -> u32 {
+    let (start, end) = bootloader_range();
+    (mbox_get_memory() - (end - start)) & !(RELOCATION_ALIGN - 1)
+}
+
 unsafe fn zero_out_bss() {
     gcc_mb();
     let bss_start_ptr = &__bss_start__;
@@ -59,7 +106,7 @@ extern "C" fn rpi_reboot() {
 }
 
 pub fn wait_for_data(timeout: Option<Duration>) {
-    let packet = BOOT_OP::GET_PROG_INFO.val().to_le_bytes();
+    let packet = Frame::GetProgInfo.encode();
     loop {
         uart::write_bytes(&packet);
         if can_read_timeout(Duration::from_millis(300)) {
@@ -68,72 +115,189 @@ pub fn wait_for_data(timeout: Option<Duration>) {
     }
 }
 
+fn send(frame: Frame) {
+    write_bytes(&frame.encode());
+}
+
 fn debug_print(s: &str) {
-    let s_bytes = s.as_bytes();
+    send(Frame::PrintString {
+        len: s.len() as u32,
+    });
+    write_bytes(s.as_bytes());
+}
+
+// Read bytes until a whole frame header has arrived. Fails with BAD_OP on bytes
+// that are not a frame and with TIMEOUT (waiting for <want>) once the line goes
+// quiet for <timeout>.
+fn recv_frame(decoder: &mut Decoder, timeout: Duration, want: BOOT_OP) -> Result<Frame, Failure> {
+    let mut byte = [0u8; 1];
+    loop {
+        if !read_bytes_timeout(&mut byte, timeout) {
+            decoder.reset();
+            return Err(Failure::new(BOOT_ERR::TIMEOUT, want.val()));
+        }
 
-    let mut write_buf = [0u8; 4 + 4];
-    write_buf[..4].copy_from_slice(&BOOT_OP::PRINT_STRING.val().to_le_bytes());
-    write_buf[4..].copy_from_slice(&s_bytes.len().to_le_bytes());
-    write_bytes(&write_buf);
-    write_bytes(s_bytes);
+        match decoder.push(byte[0]) {
+            Ok(Some(frame)) => return Ok(frame),
+            Ok(None) => {}
+            Err(e) => {
+                decoder.reset();
+                let op = match e {
+                    DecodeError::UnknownOp(op) => op,
+                    DecodeError::UnexpectedOp(op) => op.val(),
+                    _ => want.val(),
+                };
+                return Err(Failure::new(BOOT_ERR::BAD_OP, op));
+            }
+        }
+    }
 }
 
+// Throw away whatever is left of a broken chunk so the next header starts clean.
+fn drain() {
+    let mut byte = [0u8; 1];
+    while read_bytes_timeout(&mut byte, CHUNK_IDLE_TIMEOUT) {}
+}
 
-unsafe fn main() {
+// Receive <code> as [PUT_CHUNK, index, nbytes, crc32, data] packets, acking each one.
+// A chunk that is cut short, corrupted or out of order is NAKed with the index we
+// want next; the unix side resends it. A chunk we already have (our ACK got lost)
+// is accepted again and re-ACKed. If nothing arrives for MAX_IDLE_NAKS timeouts
+// in a row we give up.
+fn receive_chunks(decoder: &mut Decoder, code: &mut [u8]) -> Result<(), Failure> {
+    let chunk_size = BOOT_CHUNK_SIZE as usize;
+    let n_chunks = code.len().div_ceil(chunk_size);
+    let mut expected = 0;
+    let mut idle_naks = 0;
+
+    while expected < n_chunks {
+        let nak_expected = Frame::ChunkNak {
+            index: expected as u32,
+        };
+
+        let (index, len, crc) = match recv_frame(decoder, CHUNK_IDLE_TIMEOUT, BOOT_OP::PUT_CHUNK) {
+            Ok(Frame::PutChunk { index, nbytes, crc }) => (index as usize, nbytes as usize, crc),
+            Ok(_) => {
+                drain();
+                send(nak_expected);
+                continue;
+            }
+            Err(failure) if failure.code == BOOT_ERR::TIMEOUT => {
+                idle_naks += 1;
+                if idle_naks >= MAX_IDLE_NAKS {
+                    return Err(failure);
+                }
+                send(nak_expected);
+                continue;
+            }
+            Err(_) => {
+                drain();
+                send(nak_expected);
+                continue;
+            }
+        };
+        idle_naks = 0;
+
+        // The index came off the line: check it before it becomes an offset.
+        if index > expected || len != chunk_size.min(code.len() - index * chunk_size) {
+            drain();
+            send(nak_expected);
+            continue;
+        }
+        let start = index * chunk_size;
+
+        let chunk = &mut code[start..start + len];
+        if !read_bytes_timeout(chunk, CHUNK_IDLE_TIMEOUT) || crc32fast::hash(chunk) != crc {
+            drain();
+            send(Frame::ChunkNak {
+                index: index as u32,
+            });
+            continue;
+        }
+
+        send(Frame::ChunkAck {
+            index: index as u32,
+            crc,
+        });
+        if index == expected {
+            expected += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// One boot attempt: handshake, then receive and check the code. Returns the
+// address to jump to.
+unsafe fn load(decoder: &mut Decoder, dram_end: u32) -> Result<u32, Failure> {
     wait_for_data(None);
 
     // Got something to read, read and get the program info
-    // [PUT_PROG_INFO, addr, nbytes, cksum] = 4x4 bytes
-    let mut buf = [0u8; 16];
-    read_bytes(&mut buf);
-
-    let mut chunks = buf.chunks(4);
-    let op = u32::from_le_bytes(chunks.next().unwrap().try_into().unwrap());
-    let addr = u32::from_le_bytes(chunks.next().unwrap().try_into().unwrap());
-    let nbytes = u32::from_le_bytes(chunks.next().unwrap().try_into().unwrap());
-    let cksum = u32::from_le_bytes(chunks.next().unwrap().try_into().unwrap());
-    assert_eq!(op, BOOT_OP::PUT_PROG_INFO.val());
-
-    // Check Collision
-    if addr + nbytes > 0x200000 {
-        let write_buf = BOOT_OP::BOOT_ERROR.val().to_le_bytes();
-        write_bytes(&write_buf);
-        return;
-    }
+    // [PUT_PROG_INFO, addr, nbytes, cksum]
+    let (addr, nbytes, cksum) =
+        match recv_frame(decoder, PROG_INFO_TIMEOUT, BOOT_OP::PUT_PROG_INFO)? {
+            Frame::PutProgInfo { addr, nbytes, crc } => (addr, nbytes, crc),
+            other => return Err(Failure::new(BOOT_ERR::BAD_OP, other.op().val())),
+        };
 
-    // Echo back GET_CODE
-    let mut write_buf = [0u8; 8];
-    write_buf[..4].copy_from_slice(&BOOT_OP::GET_CODE.val().to_le_bytes());
-    write_buf[4..].copy_from_slice(&cksum.to_le_bytes());
-    write_bytes(&write_buf);
+    // Check Collision: the code has to fit in DRAM and must not hit our own copy.
+    let end = addr as u64 + nbytes as u64;
+    if end > dram_end as u64 {
+        return Err(Failure::new(BOOT_ERR::TOO_LARGE, dram_end));
+    }
+    let (boot_start, boot_end) = bootloader_range();
+    if (addr as u64) < boot_end as u64 && end > boot_start as u64 {
+        return Err(Failure::new(BOOT_ERR::OVERLAP, boot_start));
+    }
 
-    // Expect [PUT_CODE, CODE]
-    let mut op_buf = [0u8; 4];
-    read_bytes(&mut op_buf);
-    assert_eq!(u32::from_le_bytes(op_buf), BOOT_OP::PUT_CODE.val());
+    // Ask for the code in chunks: [GET_CODE_CHUNKED, cksum, chunk_size]
+    send(Frame::GetCodeChunked {
+        crc: cksum,
+        chunk_size: BOOT_CHUNK_SIZE,
+    });
 
     let code_begin_ptr = with_exposed_provenance_mut::<u8>(addr as usize);
-    let bytes_array_ptr = core::slice::from_raw_parts_mut(code_begin_ptr, nbytes as usize);
-    read_bytes(bytes_array_ptr);
+    let bytes_array_ptr =
+        unsafe { core::slice::from_raw_parts_mut(code_begin_ptr, nbytes as usize) };
+    receive_chunks(decoder, bytes_array_ptr)?;
 
     let recv_crc32 = crc32fast::hash(bytes_array_ptr);
-
     if recv_crc32 != cksum {
-        let write_buf = BOOT_OP::BOOT_ERROR.val().to_le_bytes();
-        write_bytes(&write_buf);
-        return;
+        return Err(Failure::new(BOOT_ERR::CRC_MISMATCH, recv_crc32));
     }
 
-    // Return boot success
-    let write_buf = BOOT_OP::BOOT_SUCCESS.val().to_le_bytes();
-    write_bytes(&write_buf);
+    Ok(addr)
+}
 
-    // Add name
-    let name = "Jiaye Zou: BootLoader starting.\n";
-    write_bytes(name.as_bytes());
+unsafe fn main() {
+    let mut decoder = Decoder::new(BOOT_CHUNK_SIZE);
+    // ARM memory starts at 0; the mailbox reports how far it goes.
+    let dram_end = mbox_get_memory();
 
-    // Flush
-    uart::flush();
+    loop {
+        match load(&mut decoder, dram_end) {
+            Ok(addr) => {
+                // Return boot success
+                send(Frame::BootSuccess);
+
+                // Add name
+                let name = "Jiaye Zou: BootLoader starting.\n";
+                write_bytes(name.as_bytes());
+
+                // Flush
+                uart::flush();
 
-    BRANCHTO(addr);
+                BRANCHTO(addr);
+            }
+            Err(Failure { code, detail }) => {
+                // Tell the unix side why, then wait for a fresh handshake.
+                send(Frame::BootError {
+                    code: code.val(),
+                    detail,
+                });
+                drain();
+                decoder.reset();
+            }
+        }
+    }
 }
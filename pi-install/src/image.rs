@@ -0,0 +1,116 @@
+use object::Endianness;
+use object::elf::{ELFMAG, PT_LOAD};
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// A flat memory image ready to be sent to the bootloader.
+///
+/// `code` is copied to `addr` byte for byte, and the bootloader branches to `addr` once the
+/// transfer is done, so `entry` has to be equal to `addr` for the image to boot.
+#[derive(Debug)]
+pub struct Image {
+    pub addr: u32,
+    pub entry: u32,
+    pub code: Vec<u8>,
+}
+
+/// A single `PT_LOAD` segment: `data` goes at `addr`, the rest up to `mem_size` is zero filled.
+struct Segment<'a> {
+    addr: u32,
+    mem_size: u32,
+    data: &'a [u8],
+}
+
+fn invalid_data<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads `file` and turns it into an [`Image`].
+///
+/// ELF files are laid out using their `PT_LOAD` segments and entry point. Anything else is
+/// treated as a raw binary (e.g. the output of `objcopy -O binary`) to be loaded at `raw_addr`.
+pub fn read_image(file: &Path, raw_addr: u32) -> Result<Image, Error> {
+    let data = fs::read(file)?;
+
+    if data.starts_with(&ELFMAG) {
+        return image_from_elf(&data);
+    }
+
+    Ok(Image {
+        addr: raw_addr,
+        entry: raw_addr,
+        code: data,
+    })
+}
+
+fn image_from_elf(data: &[u8]) -> Result<Image, Error> {
+    let elf = ElfFile32::<Endianness>::parse(data).map_err(invalid_data)?;
+    let endian = elf.endian();
+
+    let mut segments = Vec::new();
+    for header in elf.elf_program_headers() {
+        if header.p_type(endian) != PT_LOAD || header.p_memsz(endian) == 0 {
+            continue;
+        }
+
+        let segment_data = header
+            .data(endian, data)
+            .map_err(|_| invalid_data("PT_LOAD segment data is outside of the file"))?;
+
+        if segment_data.len() as u32 > header.p_memsz(endian) {
+            return Err(invalid_data("PT_LOAD segment has p_filesz > p_memsz"));
+        }
+
+        segments.push(Segment {
+            // Physical address: this is where the bytes have to end up in memory.
+            addr: header.p_paddr(endian),
+            mem_size: header.p_memsz(endian),
+            data: segment_data,
+        });
+    }
+
+    if segments.is_empty() {
+        return Err(invalid_data("ELF file has no PT_LOAD segments"));
+    }
+
+    segments.sort_by_key(|s| s.addr);
+
+    let addr = segments[0].addr;
+    let end = segments
+        .iter()
+        .map(|s| s.addr as u64 + s.mem_size as u64)
+        .max()
+        .unwrap();
+    if end > u32::MAX as u64 {
+        return Err(invalid_data("PT_LOAD segments extend past 4 GiB"));
+    }
+
+    // Gaps between segments and .bss (mem_size > file_size) are sent as zeros.
+    let mut code = vec![0u8; (end - addr as u64) as usize];
+    for (i, s) in segments.iter().enumerate() {
+        if let Some(prev) = i.checked_sub(1).map(|p| &segments[p])
+            && prev.addr + prev.mem_size > s.addr
+        {
+            return Err(invalid_data(format!(
+                "PT_LOAD segments at {:#x} and {:#x} overlap",
+                prev.addr, s.addr
+            )));
+        }
+
+        let offset = (s.addr - addr) as usize;
+        code[offset..offset + s.data.len()].copy_from_slice(s.data);
+    }
+
+    let entry = elf.elf_header().e_entry(endian);
+    if entry != addr {
+        return Err(invalid_data(format!(
+            "entry point {:#x} is not the load address {:#x}; the bootloader branches to the \
+             load address",
+            entry, addr
+        )));
+    }
+
+    Ok(Image { addr, entry, code })
+}
@@ -0,0 +1,197 @@
+pub mod image;
+pub mod profile;
+pub mod report;
+pub mod sim;
+pub mod symbols;
+pub mod terminal;
+pub mod transfer;
+pub mod transport;
+
+use crate::image::Image;
+use crate::transport::Transport;
+use boot_proto::{BOOT_ERR, BOOT_OP, Decoder, Encoder, Frame};
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Read timeout while talking to the bootloader.
+pub const PORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a chunk to be acked before resending it.
+pub const CHUNK_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn context(what: &'static str) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+/// Reads the next frame header, skipping (and reporting) bytes that are not one.
+pub fn read_frame<P: Read>(port: &mut P, decoder: &mut Decoder) -> io::Result<Frame> {
+    let mut byte = [0u8; 1];
+    loop {
+        port.read_exact(&mut byte)?;
+        match decoder.push(byte[0]) {
+            Ok(Some(frame)) => return Ok(frame),
+            Ok(None) => {}
+            Err(e) => println!("Discarding a byte: {:?}", e),
+        }
+    }
+}
+
+/// Writes `frame` followed by its payload.
+pub fn write_frame<P: Write>(port: &mut P, frame: Frame, payload: &[u8]) -> io::Result<()> {
+    let bytes = Encoder::new(frame, payload)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{:?}", e)))?
+        .collect::<Vec<_>>();
+    port.write_all(&bytes)
+}
+
+/// Reads the text following a `PRINT_STRING` header. Lengths past
+/// [`MAX_PAYLOAD_LEN`](terminal::MAX_PAYLOAD_LEN) come from a damaged header and are refused.
+pub fn read_string<P: Read>(port: &mut P, len: u32) -> io::Result<String> {
+    if len as usize > terminal::MAX_PAYLOAD_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("string of {} bytes announced", len),
+        ));
+    }
+    let mut string = vec![0u8; len as usize];
+    port.read_exact(&mut string)?;
+    Ok(String::from_utf8_lossy(&string).into_owned())
+}
+
+/// Explains the `BOOT_ERROR` the bootloader sent while booting `image`.
+pub fn diagnose(code: u32, detail: u32, image: &Image) -> String {
+    let end = image.addr as u64 + image.code.len() as u64;
+    match BOOT_ERR::from_u32(code) {
+        Some(BOOT_ERR::BAD_OP) => format!(
+            "the bootloader got {} instead of a request; is something else writing to the port?",
+            describe_op(detail)
+        ),
+        Some(BOOT_ERR::TOO_LARGE) => format!(
+            "the image at {:#x}..{:#x} does not fit in the pi's {:#x} bytes of DRAM",
+            image.addr, end, detail
+        ),
+        Some(BOOT_ERR::OVERLAP) => format!(
+            "the image at {:#x}..{:#x} runs into the bootloader at {:#x}",
+            image.addr, end, detail
+        ),
+        Some(BOOT_ERR::CRC_MISMATCH) => format!(
+            "the code arrived with crc32 {:x}, expected {:x}; the line is corrupting data",
+            detail,
+            crc32fast::hash(&image.code)
+        ),
+        Some(BOOT_ERR::TIMEOUT) => format!(
+            "the bootloader timed out waiting for {}",
+            describe_op(detail)
+        ),
+        None => format!("unknown error code {} (detail {:#x})", code, detail),
+    }
+}
+
+fn describe_op(op: u32) -> String {
+    match BOOT_OP::from_u32(op) {
+        Some(op) => format!("{:?}", op),
+        None => format!("{:#010x}, which is not a BOOT_OP", op),
+    }
+}
+
+fn boot_error(code: u32, detail: u32, image: &Image) -> io::Error {
+    io::Error::other(format!(
+        "Bootloader sent BOOT_ERROR: {}",
+        diagnose(code, detail, image)
+    ))
+}
+
+/// Runs the `BOOT_OP` exchange with the bootloader on `port` until it reports `BOOT_SUCCESS`.
+///
+/// Both the chunked transfer (`GET_CODE_CHUNKED`) and the single `PUT_CODE` burst of older
+/// bootloaders are supported; the bootloader picks one. On success the kernel is running and
+/// everything read from `port` afterwards is its output. On `BOOT_ERROR` the bootloader goes
+/// back to waiting for a handshake, so `boot` can simply be called again.
+pub fn boot<T: Transport>(port: &mut T, image: &Image) -> io::Result<()> {
+    let program = &image.code;
+    let crc_32 = crc32fast::hash(program);
+    let nbytes = program.len();
+
+    // The bootloader only sends short strings: a longer length is a damaged header.
+    let mut decoder = Decoder::new(terminal::MAX_PAYLOAD_LEN as u32);
+    let mut get_prog_handled = false;
+    loop {
+        let frame =
+            read_frame(port, &mut decoder).map_err(context("Waiting for the bootloader"))?;
+
+        match frame {
+            Frame::GetProgInfo => {
+                if get_prog_handled {
+                    continue;
+                }
+
+                println!("Sending program info...");
+
+                let prog_info = Frame::PutProgInfo {
+                    addr: image.addr,
+                    nbytes: nbytes as u32,
+                    crc: crc_32,
+                };
+                write_frame(port, prog_info, &[])
+                    .map_err(context("Failed to write program info"))?;
+
+                get_prog_handled = true;
+            }
+            Frame::GetCode { crc } => {
+                println!("Sending code...");
+
+                // Check CRC32 is the same
+                check_crc_echo(crc, crc_32)?;
+
+                write_frame(port, Frame::PutCode, program)
+                    .map_err(context("Failed to write code"))?;
+            }
+            Frame::GetCodeChunked { crc, chunk_size } => {
+                check_crc_echo(crc, crc_32)?;
+                if chunk_size == 0 {
+                    return Err(io::Error::other("Bootloader asked for empty chunks"));
+                }
+
+                println!("Sending code in {} byte chunks...", chunk_size);
+
+                port.set_timeout(CHUNK_ACK_TIMEOUT)?;
+                let stats = transfer::send_chunked(port, &mut decoder, image, chunk_size as usize)
+                    .map_err(context("Failed to send code"))?;
+                port.set_timeout(PORT_TIMEOUT)?;
+
+                println!(
+                    "Sent {} chunks in {:.2?} ({:.1} KiB/s), {} retransmits",
+                    stats.chunks,
+                    stats.elapsed,
+                    nbytes as f64 / 1024.0 / stats.elapsed.as_secs_f64(),
+                    stats.retransmits
+                );
+            }
+            Frame::BootError { code, detail } => return Err(boot_error(code, detail, image)),
+            Frame::BootSuccess => {
+                println!("Boot successful, starting...");
+                return Ok(());
+            }
+            Frame::PrintString { len } => {
+                let string = read_string(port, len).map_err(context("Failed to read string"))?;
+                println!("PI: {}", string);
+            }
+            Frame::KernelExit { code } => {
+                return Err(io::Error::other(format!(
+                    "Kernel exited with code {} before the bootloader reported a boot",
+                    code as i32
+                )));
+            }
+            x => println!("Unimplemented OP: {:?}", x.op()),
+        }
+    }
+}
+
+fn check_crc_echo(echo: u32, crc_32: u32) -> io::Result<()> {
+    if echo != crc_32 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Bootloader echoed crc32 {:x}, expected {:x}", echo, crc_32),
+        ));
+    }
+    Ok(())
+}
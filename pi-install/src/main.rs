@@ -1,23 +1,27 @@
 extern crate core;
 
-use bytes::{BufMut, BytesMut};
 use clap::Parser;
-use constants::BOOT_OP::{PUT_CODE, PUT_PROG_INFO};
-use constants::{ARM_BASE, BOOT_OP, UART_BAUD_RATE};
+use constants::{ARM_BASE, UART_BAUD_RATE};
+use pi_install::PORT_TIMEOUT;
+use pi_install::image;
+use pi_install::symbols;
+use pi_install::terminal::{self, Input, POLL_PERIOD};
+use pi_install::transport::Transport;
 use serialport::Error;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
 use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::Duration;
 
 #[derive(Parser, Debug)]
 struct PiInstall {
     #[arg(short, long, default_value_t = UART_BAUD_RATE)]
     baud: u32,
 
-    #[arg(short, long, default_value = "0x8000")]
-    addr: String,
+    /// Load address used when the kernel is a raw binary rather than an ELF file.
+    #[arg(short, long, default_value_t = ARM_BASE, value_parser = parse_addr)]
+    addr: u32,
 
     #[arg(short, long, default_value = "true")]
     last: bool,
@@ -28,9 +32,42 @@ struct PiInstall {
     #[arg(short, long)]
     device: Option<PathBuf>,
 
+    /// Forward keystrokes to the pi after boot. Ctrl-A x quits, Ctrl-A Ctrl-A sends Ctrl-A.
+    #[arg(short, long)]
+    interactive: bool,
+
+    /// Send this file to the pi after boot (`-` for stdin), then keep printing its output.
+    #[arg(short, long, conflicts_with = "interactive")]
+    script: Option<PathBuf>,
+
+    /// Write the results of a `cargo test` kernel to this file as JUnit XML.
+    #[arg(long)]
+    junit: Option<PathBuf>,
+
+    /// Print where the time went, from the PC samples the kernel dumps.
+    #[arg(long)]
+    gprof: bool,
+
+    /// How many functions and lines the `--gprof` report ranks.
+    #[arg(long, default_value_t = 20)]
+    gprof_top: usize,
+
+    /// Write the PC samples to this file as folded stacks, for `flamegraph.pl` or
+    /// `inferno-flamegraph`.
+    #[arg(long)]
+    gprof_folded: Option<PathBuf>,
+
+    /// Kernel to boot: an ELF file, or a raw binary loaded at `--addr`.
     kernel: PathBuf,
 }
 
+fn parse_addr(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
 fn find_serial_port(first: bool) -> Option<PathBuf> {
     let ports = serialport::available_ports().expect("Failed to enumerate serial ports");
 
@@ -69,7 +106,7 @@ fn open_serial_port(
             .data_bits(serialport::DataBits::Eight)
             .parity(serialport::Parity::None)
             .stop_bits(serialport::StopBits::One)
-            .timeout(Duration::from_secs(5))
+            .timeout(PORT_TIMEOUT)
             .open()
         {
             Ok(port) => return Ok(port),
@@ -83,19 +120,6 @@ fn open_serial_port(
     Err(last_error.unwrap())
 }
 
-fn get_op(buf: &[u8; 4]) -> Option<BOOT_OP> {
-    let op_code = u32::from_le_bytes(*buf);
-
-    BOOT_OP::from_u32(op_code)
-}
-
-fn read_code(file: &PathBuf) -> Result<Vec<u8>, Error> {
-    let mut f = fs::File::open(file)?;
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf)?;
-    Ok(buf)
-}
-
 fn main() {
     let args = PiInstall::parse();
 
@@ -119,87 +143,107 @@ fn main() {
     /*
        READ PROGRAM
     */
-    let program = read_code(&args.kernel).expect(format!("Failed to read program at {}", args.kernel.display()).as_str() );
-    let crc_32 = crc32fast::hash(&program);
-    let nbytes = program.len();
-    println!("Program size: {} bytes, crc32={:x}", nbytes, crc_32);
+    let image = image::read_image(&args.kernel, args.addr)
+        .unwrap_or_else(|e| panic!("Failed to read program at {}: {}", args.kernel.display(), e));
+    println!(
+        "Program size: {} bytes, load addr={:#x}, entry={:#x}, crc32={:x}",
+        image.code.len(),
+        image.addr,
+        image.entry,
+        crc32fast::hash(&image.code)
+    );
+
+    // Addresses in the kernel's output are named using its ELF file, when it has one.
+    let symbols = match symbols::read_symbols(&args.kernel) {
+        Ok(symbols) => symbols,
+        Err(e) => {
+            println!("Not naming addresses, cannot read symbols: {}", e);
+            None
+        }
+    };
+    if let Some(symbols) = &symbols {
+        println!("Naming addresses with {} functions", symbols.len());
+    }
 
     /*
        MAIN LOOP
     */
-    let mut get_prog_handled = false;
-    let mut buf = [0u8; 4];
-    while let Ok(()) = port.read_exact(&mut buf) {
-        match get_op(&buf) {
-            Some(BOOT_OP::GET_PROG_INFO) => {
-                if get_prog_handled {
-                    continue;
-                }
-
-                println!("Sending program info...");
-
-                let mut write_buf = BytesMut::with_capacity(4 * 4);
-                write_buf.put_u32_le(PUT_PROG_INFO.into());
-                write_buf.put_u32_le(ARM_BASE);
-                write_buf.put_u32_le(nbytes as u32);
-                write_buf.put_u32_le(crc_32);
-                port.write_all(&write_buf)
-                    .expect("Failed to write program info");
-
-                get_prog_handled = true;
-            }
-            Some(BOOT_OP::GET_CODE) => {
-
-                println!("Sending code...");
-
-                // Check CRC32 is the same
-                port.read_exact(&mut buf)
-                    .expect("Failed to read CRC32 echo");
-
-                let crc_32_echo = u32::from_le_bytes(buf);
-                assert_eq!(crc_32_echo, crc_32);
-
-                let mut write_buf = BytesMut::with_capacity(4 + nbytes);
-                write_buf.put_u32_le(PUT_CODE.into());
-                write_buf.put_slice(&program);
-                port.write_all(&write_buf).expect("Failed to write code");
-            }
-            Some(BOOT_OP::BOOT_ERROR) => panic!("Boot failed"),
-            Some(BOOT_OP::BOOT_SUCCESS) => {
-                println!("Boot successful, starting...");
-                port.set_timeout(Duration::from_hours(1)).expect("Failed to set timeout");
-                break;
-            },
-            Some(BOOT_OP::PRINT_STRING) => {
-                port.read_exact(&mut buf)
-                    .expect("Failed to read string length");
-
-                let n_read = u32::from_le_bytes(buf);
-                let mut string = vec![0u8; n_read as usize];
-                port.read_exact(&mut string).expect("Failed to read string");
-
-                println!("PI: {}", String::from_utf8(string).unwrap());
-            }
-            Some(x) => println!("Unimplemented OP: {:?}", x),
-            None => {
-                println!("Unknown op code discarding: {:?}", buf);
-                port.read_exact(&mut [0u8; 1])
-                    .expect("Failed to skip a byte");
-            }
+    if let Err(e) = pi_install::boot(&mut port, &image) {
+        eprintln!("Boot failed: {}", e);
+        std::process::exit(1);
+    }
+    port.set_timeout(POLL_PERIOD)
+        .expect("Failed to set timeout");
+
+    let tx = port.try_clone().expect("Failed to clone serial port");
+    let input: Option<Input<Box<dyn Read + Send>>> = match (&args.script, args.interactive) {
+        (Some(path), _) if path.as_os_str() == "-" => Some(Input::Script(Box::new(io::stdin()))),
+        (Some(path), _) => {
+            let file = fs::File::open(path)
+                .unwrap_or_else(|e| panic!("Failed to open script {}: {}", path.display(), e));
+            Some(Input::Script(Box::new(file)))
         }
+        (None, true) => Some(Input::Interactive(Box::new(io::stdin()))),
+        (None, false) => None,
+    };
+
+    if args.interactive {
+        println!("Interactive mode, Ctrl-A x to quit.");
     }
+    // Elsewhere keys arrive a line at a time.
+    #[cfg(unix)]
+    let raw_mode = args.interactive.then(|| {
+        terminal::RawMode::enable(io::stdin().as_raw_fd()).expect("Failed to set raw mode")
+    });
 
     println!("Starts to print output from PI:");
 
-    let mut read_buf = [0u8; 1024];
-    while let Ok(n) = port.read(&mut read_buf) {
-        if n == 0 {
-            sleep(Duration::from_micros(1000));
-            continue
+    let result = terminal::session(&mut port, tx, input, &mut io::stdout(), symbols.as_ref());
+    #[cfg(unix)]
+    drop(raw_mode);
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("\nLost the pi: {}", e);
+            std::process::exit(1);
         }
+    };
 
-        let output = String::from_utf8_lossy(&read_buf[..n]);
-        print!("{}", output);
+    if !outcome.tests.is_empty() {
+        println!("\n{}", outcome.tests.summary());
+    }
+    if args.gprof || args.gprof_folded.is_some() {
+        match &symbols {
+            _ if outcome.profile.is_empty() => println!("\nThe kernel dumped no gprof samples"),
+            Some(symbols) => {
+                if args.gprof {
+                    println!("\n{}", outcome.profile.report(symbols, args.gprof_top));
+                }
+                if let Some(path) = &args.gprof_folded {
+                    fs::write(path, outcome.profile.folded(symbols))
+                        .unwrap_or_else(|e| panic!("Failed to write {}: {}", path.display(), e));
+                    println!("Wrote folded stacks to {}", path.display());
+                }
+            }
+            None => println!("\nCannot name gprof samples without the kernel's ELF file"),
+        }
+    }
+    if let Some(path) = &args.junit {
+        let suite = args
+            .kernel
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        fs::write(path, outcome.tests.junit(&suite))
+            .unwrap_or_else(|e| panic!("Failed to write {}: {}", path.display(), e));
+        println!("Wrote test results to {}", path.display());
     }
 
+    if let Some(code) = outcome.exit_code {
+        println!("\nKernel exited with code {}", code);
+        std::process::exit(code);
+    }
+    if !outcome.tests.is_empty() && !outcome.tests.passed() {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,181 @@
+//! Profiles from the `gprof` kernel's PC samples.
+//!
+//! The kernel samples the PC from the timer interrupt. `crab_pi::profiler` dumps the samples in
+//! `PROFILE_SAMPLES` frames; the `gprof` lab prints one `addr: count` line per sampled word,
+//! with the address as `{:08x}`, which [`DumpScanner`] picks out of the text. Either way they
+//! end up in a [`Profile`], which ranks them by function and by source line against the
+//! kernel's [`Symbols`] and writes them as folded stacks, the input format of `flamegraph.pl`
+//! and `inferno-flamegraph`.
+
+use crate::symbols::Symbols;
+use boot_proto::PROFILE_SAMPLE_LEN;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Where samples outside every known function are counted.
+const UNKNOWN: &str = "[unknown]";
+/// Longest line kept: a sample line is much shorter, even annotated.
+const MAX_LINE_LEN: usize = 4096;
+
+/// The sample on a dump line, if it is one. Annotations `pi-install` added after the address
+/// are skipped, so `00008254 <trivial>: 12` is a sample too.
+pub fn parse_sample(line: &str) -> Option<(u64, u64)> {
+    let (head, count) = line.trim_end().rsplit_once(": ")?;
+    let (addr, annotation) = head.split_at_checked(8)?;
+    if !(annotation.is_empty() || annotation.starts_with(" <") && annotation.ends_with('>')) {
+        return None;
+    }
+    if !addr.bytes().all(|b| b.is_ascii_hexdigit()) || !count.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((u64::from_str_radix(addr, 16).ok()?, count.parse().ok()?))
+}
+
+/// PC samples: how many times each address was seen.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    samples: HashMap<u64, u64>,
+    /// How often the PC was sampled, when the kernel said.
+    period_usec: Option<u32>,
+}
+
+/// One row of a ranking.
+struct Row {
+    name: String,
+    count: u64,
+}
+
+impl Profile {
+    /// Adds `count` samples at `addr`. An address dumped twice counts twice.
+    pub fn add(&mut self, addr: u64, count: u64) {
+        *self.samples.entry(addr).or_default() += count;
+    }
+
+    /// Adds the payload of a `PROFILE_SAMPLES` frame; a trailing partial sample is ignored.
+    pub fn add_frame(&mut self, period_usec: u32, payload: &[u8]) {
+        self.period_usec = Some(period_usec);
+        for sample in payload.as_chunks::<PROFILE_SAMPLE_LEN>().0 {
+            let (addr, hits) = sample.split_at(4);
+            let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap()) as u64;
+            self.add(word(addr), word(hits));
+        }
+    }
+
+    /// How many samples there are.
+    pub fn total(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Sums the samples by `key`, most first.
+    fn rank(&self, key: impl Fn(u64) -> String) -> Vec<Row> {
+        let mut counts = HashMap::<String, u64>::new();
+        for (&addr, &count) in &self.samples {
+            *counts.entry(key(addr)).or_default() += count;
+        }
+        let mut rows = counts
+            .into_iter()
+            .map(|(name, count)| Row { name, count })
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        rows
+    }
+
+    /// The `top` functions and the `top` source lines with the most samples, with their share
+    /// of all samples.
+    pub fn report(&self, symbols: &Symbols, top: usize) -> String {
+        let total = self.total();
+        let function = |addr| {
+            symbols
+                .function(addr)
+                .map_or(UNKNOWN.to_string(), |(name, _)| name.to_string())
+        };
+        let line = |addr| match symbols.line(addr) {
+            Some(line) => format!("{} ({})", line, function(addr)),
+            None => format!("{:#x} ({})", addr, function(addr)),
+        };
+
+        let mut report = format!("{} samples at {} addresses", total, self.samples.len());
+        if let Some(period) = self.period_usec {
+            let _ = write!(
+                report,
+                ", one every {} usec, {:.1} ms in all",
+                period,
+                (total * period as u64) as f64 / 1000.0
+            );
+        }
+        report.push('\n');
+        for (title, rows) in [("function", self.rank(function)), ("line", self.rank(line))] {
+            let _ = writeln!(report, "\n  %       samples  {}", title);
+            for row in rows.iter().take(top) {
+                let _ = writeln!(
+                    report,
+                    "{:6.2}%  {:8}  {}",
+                    row.count as f64 * 100.0 / total as f64,
+                    row.count,
+                    row.name
+                );
+            }
+            if rows.len() > top {
+                let _ = writeln!(report, "  ... {} more", rows.len() - top);
+            }
+        }
+        report
+    }
+
+    /// The samples as folded stacks: `outer;inlined;... count` per line, sorted.
+    ///
+    /// Only the PC is sampled, so a stack is the function it was in plus whatever the debug
+    /// info says was inlined there, not the real call stack.
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut rows = self.rank(|addr| match symbols.frames(addr) {
+            frames if frames.is_empty() => UNKNOWN.to_string(),
+            frames => frames.join(";"),
+        });
+        rows.sort_by(|a, b| a.name.cmp(&b.name));
+        rows.iter()
+            .map(|row| format!("{} {}\n", row.name, row.count))
+            .collect()
+    }
+}
+
+/// Picks the samples the `gprof` lab prints out of kernel output.
+#[derive(Debug, Default)]
+pub struct DumpScanner {
+    /// The line so far, or as much of it as a sample could be.
+    line: Vec<u8>,
+    /// The line is too long to be a sample.
+    long: bool,
+}
+
+impl DumpScanner {
+    /// Feeds kernel output, adding the samples on each line it completes to `profile`.
+    pub fn feed(&mut self, text: &[u8], profile: &mut Profile) {
+        for &byte in text {
+            match byte {
+                b'\n' => self.end_line(profile),
+                b'\r' => {}
+                _ if self.line.len() == MAX_LINE_LEN => self.long = true,
+                _ => self.line.push(byte),
+            }
+        }
+    }
+
+    /// Counts a last line that did not end.
+    pub fn finish(&mut self, profile: &mut Profile) {
+        self.end_line(profile);
+    }
+
+    fn end_line(&mut self, profile: &mut Profile) {
+        if !self.long
+            && let Some((addr, count)) = parse_sample(&String::from_utf8_lossy(&self.line))
+        {
+            profile.add(addr, count);
+        }
+        self.line.clear();
+        self.long = false;
+    }
+}
@@ -0,0 +1,210 @@
+//! Results of a kernel built by `cargo test`.
+//!
+//! `crab_pi::testing` sends `TEST_START` before each test and `TEST_RESULT` after it; the text
+//! in between is that test's output. [`TestReport`] collects them, then gives a summary and
+//! JUnit XML.
+
+use boot_proto::TEST_STATUS;
+use std::fmt::Write;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    Passed,
+    /// It panicked.
+    Failed,
+    TimedOut,
+    /// It started but no result came: the kernel died or the session ended.
+    NoResult,
+}
+
+#[derive(Clone, Debug)]
+pub struct TestCase {
+    /// Full path, e.g. `thread::t3_test_exit::t3_test_exit`.
+    pub name: String,
+    pub status: Status,
+    pub usec: u32,
+    pub output: Vec<u8>,
+}
+
+impl TestCase {
+    /// Everything in the name up to the last `::`, the part JUnit calls the class.
+    fn classname(&self) -> &str {
+        self.name.rsplit_once("::").map_or("", |(class, _)| class)
+    }
+
+    fn short_name(&self) -> &str {
+        self.name
+            .rsplit_once("::")
+            .map_or(&self.name, |(_, name)| name)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TestReport {
+    /// How many tests the kernel has, from `TEST_START`.
+    pub total: usize,
+    /// Tests in the order they started.
+    pub cases: Vec<TestCase>,
+}
+
+impl TestReport {
+    /// True if the kernel never started a test.
+    pub fn is_empty(&self) -> bool {
+        self.cases.is_empty()
+    }
+
+    /// Test `index` of `total` started.
+    pub fn start(&mut self, index: u32, total: u32, name: String) {
+        self.total = total as usize;
+        if self.cases.len() != index as usize {
+            println!(
+                "Test {} started after {} others; results may be mixed up",
+                index,
+                self.cases.len()
+            );
+        }
+        self.cases.push(TestCase {
+            name,
+            status: Status::NoResult,
+            usec: 0,
+            output: Vec::new(),
+        });
+    }
+
+    /// Kernel output, which belongs to the running test if there is one.
+    pub fn output(&mut self, text: &[u8]) {
+        if let Some(case) = self.cases.last_mut()
+            && case.status == Status::NoResult
+        {
+            case.output.extend_from_slice(text);
+        }
+    }
+
+    /// Test `index` ended. Returns the case, or `None` if it never started.
+    pub fn finish(&mut self, index: u32, status: u32, usec: u32) -> Option<&TestCase> {
+        let case = self.cases.get_mut(index as usize)?;
+        case.status = match TEST_STATUS::from_u32(status) {
+            Some(TEST_STATUS::PASS) => Status::Passed,
+            Some(TEST_STATUS::TIMEOUT) => Status::TimedOut,
+            Some(TEST_STATUS::FAIL) | None => Status::Failed,
+        };
+        case.usec = usec;
+        Some(case)
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.cases.iter().filter(|c| c.status == status).count()
+    }
+
+    /// Tests the kernel has but never started.
+    pub fn not_run(&self) -> usize {
+        self.total.saturating_sub(self.cases.len())
+    }
+
+    /// True if every test ran and passed.
+    pub fn passed(&self) -> bool {
+        self.not_run() == 0 && self.count(Status::Passed) == self.cases.len()
+    }
+
+    /// One line per test that did not pass, then the counts, like `cargo test` prints.
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        for case in self.cases.iter().filter(|c| c.status != Status::Passed) {
+            let _ = writeln!(summary, "    {} ({})", case.name, describe(case.status));
+        }
+        let _ = write!(
+            summary,
+            "test result: {}. {} passed; {} failed; {} timed out; {} no result; {} not run",
+            if self.passed() { "ok" } else { "FAILED" },
+            self.count(Status::Passed),
+            self.count(Status::Failed),
+            self.count(Status::TimedOut),
+            self.count(Status::NoResult),
+            self.not_run(),
+        );
+        summary
+    }
+
+    /// The report as a JUnit `<testsuites>` document with a single suite named `suite`.
+    ///
+    /// Panics and timeouts are failures, tests without a result are errors, and tests that never
+    /// started only show up in the `skipped` count. Each test's output is its `system-out`.
+    pub fn junit(&self, suite: &str) -> String {
+        let failures = self.count(Status::Failed) + self.count(Status::TimedOut);
+        let seconds = |usec: u32| usec as f64 / 1e6;
+        let total_usec = self.cases.iter().map(|c| c.usec).sum();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" \
+             skipped=\"{}\" time=\"{:.6}\">",
+            escape(suite),
+            self.total.max(self.cases.len()),
+            failures,
+            self.count(Status::NoResult),
+            self.not_run(),
+            seconds(total_usec),
+        );
+
+        for case in &self.cases {
+            let _ = writeln!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.6}\">",
+                escape(case.classname()),
+                escape(case.short_name()),
+                seconds(case.usec),
+            );
+            let problem = match case.status {
+                Status::Passed => None,
+                Status::Failed => Some(r#"<failure type="panic" message="panicked"/>"#),
+                Status::TimedOut => Some(r#"<failure type="timeout" message="timed out"/>"#),
+                Status::NoResult => Some(
+                    r#"<error type="no-result" message="the kernel stopped during the test"/>"#,
+                ),
+            };
+            if let Some(problem) = problem {
+                let _ = writeln!(xml, "      {}", problem);
+            }
+            if !case.output.is_empty() {
+                let _ = writeln!(
+                    xml,
+                    "      <system-out>{}</system-out>",
+                    escape(&String::from_utf8_lossy(&case.output))
+                );
+            }
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+/// How a test ended, as `pi-install` prints it after the test's name.
+pub fn describe(status: Status) -> &'static str {
+    match status {
+        Status::Passed => "ok",
+        Status::Failed => "FAILED",
+        Status::TimedOut => "TIMED OUT",
+        Status::NoResult => "no result",
+    }
+}
+
+/// Escapes text for XML, dropping control characters XML cannot hold.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
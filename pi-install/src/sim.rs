@@ -0,0 +1,405 @@
+//! A simulated `pi-bootloader`, so the host side of the boot protocol can be exercised
+//! without a CP2102 adapter and a board.
+//!
+//! [`FakeBootloader::spawn`] runs the pi side of `BOOT_OP` on a thread at the other end of
+//! an in-memory [`pipe`], optionally injecting the failures we see on real hardware. Like the
+//! real one, it answers a failed attempt with `BOOT_ERROR` and waits for a fresh handshake; it
+//! stops once it boots or the host hangs up.
+
+use crate::transport::{PipeEnd, Transport, pipe};
+use crate::write_frame;
+use boot_proto::{BOOT_ERR, BOOT_OP, DecodeError, Decoder, Frame};
+use constants::BOOT_CHUNK_SIZE;
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::Range;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Where the bootloader's copy may start, as in `pi-bootloader`.
+const RELOCATION_ALIGN: u32 = 0x1000;
+/// How often `GET_PROG_INFO` is repeated until the host answers.
+const PROG_INFO_PERIOD: Duration = Duration::from_millis(300);
+/// How long the line may stay quiet in the middle of a chunk.
+const CHUNK_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long `PUT_PROG_INFO` may take once the host starts answering.
+const PROG_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+/// Chunk timeouts in a row before the host is considered gone.
+const MAX_IDLE_NAKS: u32 = 30;
+
+/// Why an attempt failed: the `BOOT_ERR` and its detail word, as sent in `BOOT_ERROR`.
+pub type Failure = (BOOT_ERR, u32);
+
+/// Failures to inject into a simulated boot.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Offsets in the stream received from the host (counted from the first byte after
+    /// `PUT_PROG_INFO`) of bytes that are silently lost.
+    pub drop_bytes: Vec<usize>,
+    /// Chunks whose first copy arrives with a flipped bit. In single-shot mode any entry
+    /// corrupts the whole image.
+    pub corrupt_chunks: Vec<u32>,
+    /// Report a `CRC_MISMATCH` even when the code arrived intact.
+    pub boot_error: bool,
+}
+
+/// What the fake pi ended up with. `addr` and `code` are from the last attempt.
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub addr: u32,
+    pub code: Vec<u8>,
+    pub naks: u32,
+    pub booted: bool,
+    /// Every `BOOT_ERROR` sent, in order.
+    pub errors: Vec<Failure>,
+}
+
+/// The pi side of the boot protocol.
+#[derive(Clone, Debug)]
+pub struct FakeBootloader {
+    /// Use `GET_CODE_CHUNKED`; `false` behaves like an older, single `PUT_CODE` bootloader.
+    pub chunked: bool,
+    pub chunk_size: u32,
+    pub faults: Faults,
+    /// Written after `BOOT_SUCCESS`, as if the kernel printed it.
+    pub kernel_output: Vec<u8>,
+    /// ARM memory the fake's mailbox reports.
+    pub dram_end: u32,
+    /// Bytes the bootloader takes, code and stack, at the top of DRAM.
+    pub bootloader_size: u32,
+}
+
+impl Default for FakeBootloader {
+    fn default() -> Self {
+        Self {
+            chunked: true,
+            chunk_size: BOOT_CHUNK_SIZE,
+            faults: Faults::default(),
+            kernel_output: Vec::new(),
+            // A 512MB board with the default 64MB GPU split.
+            dram_end: 0x1C000000,
+            bootloader_size: 0x30000,
+        }
+    }
+}
+
+/// Wraps the fake's end of the line and loses the bytes listed in [`Faults::drop_bytes`].
+struct LossyPort<T> {
+    inner: T,
+    received: usize,
+    drop_at: HashSet<usize>,
+    counting: bool,
+}
+
+impl<T: Read> Read for LossyPort<T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut byte = [0u8; 1];
+            if self.inner.read(&mut byte)? == 0 {
+                return Ok(0);
+            }
+
+            if self.counting {
+                self.received += 1;
+                if self.drop_at.contains(&(self.received - 1)) {
+                    continue;
+                }
+            }
+
+            if let Some(first) = out.first_mut() {
+                *first = byte[0];
+                return Ok(1);
+            }
+            return Ok(0);
+        }
+    }
+}
+
+impl<T: Write> Write for LossyPort<T> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads a frame header. Bytes that are not one fail the read with `BAD_OP`.
+fn read_header<P: Read>(
+    port: &mut P,
+    decoder: &mut Decoder,
+    want: BOOT_OP,
+) -> io::Result<Result<Frame, Failure>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match port.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if is_timeout(&e) => {
+                decoder.reset();
+                return Ok(Err((BOOT_ERR::TIMEOUT, want.val())));
+            }
+            Err(e) => return Err(e),
+        }
+
+        match decoder.push(byte[0]) {
+            Ok(Some(frame)) => return Ok(Ok(frame)),
+            Ok(None) => {}
+            Err(e) => {
+                decoder.reset();
+                let op = match e {
+                    DecodeError::UnknownOp(op) => op,
+                    DecodeError::UnexpectedOp(op) => op.val(),
+                    _ => want.val(),
+                };
+                return Ok(Err((BOOT_ERR::BAD_OP, op)));
+            }
+        }
+    }
+}
+
+fn send<P: Write>(port: &mut P, frame: Frame) -> io::Result<()> {
+    write_frame(port, frame, &[])
+}
+
+fn nak<P: Write>(port: &mut P, index: usize, loaded: &mut Loaded) -> io::Result<()> {
+    loaded.naks += 1;
+    send(
+        port,
+        Frame::ChunkNak {
+            index: index as u32,
+        },
+    )
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::TimedOut
+}
+
+impl FakeBootloader {
+    /// Where the bootloader moves itself to, plus its stack: as high in DRAM as it fits, the
+    /// way `pi-bootloader`'s `relocation_base` places it.
+    pub fn bootloader_range(&self) -> Range<u32> {
+        let start = (self.dram_end - self.bootloader_size) & !(RELOCATION_ALIGN - 1);
+        start..start + self.bootloader_size
+    }
+
+    /// Starts the fake on its own thread; the returned end goes to [`crate::boot`].
+    pub fn spawn(self) -> (PipeEnd, JoinHandle<io::Result<Loaded>>) {
+        let (host, pi) = pipe();
+        let handle = thread::spawn(move || self.run(pi));
+        (host, handle)
+    }
+
+    /// Runs the pi side over `port` the way `pi-bootloader`'s `main` does: attempts are
+    /// repeated until one boots or the host hangs up.
+    pub fn run<T: Transport>(self, port: T) -> io::Result<Loaded> {
+        let mut port = LossyPort {
+            inner: port,
+            received: 0,
+            drop_at: self.faults.drop_bytes.iter().copied().collect(),
+            counting: false,
+        };
+        let mut decoder = Decoder::new(self.chunk_size);
+        let mut loaded = Loaded::default();
+
+        loop {
+            match self.load(&mut port, &mut decoder, &mut loaded) {
+                Ok(Ok(())) => break,
+                Ok(Err((code, detail))) => {
+                    send(
+                        &mut port,
+                        Frame::BootError {
+                            code: code.val(),
+                            detail,
+                        },
+                    )?;
+                    loaded.errors.push((code, detail));
+                    port.inner.set_timeout(CHUNK_IDLE_TIMEOUT)?;
+                    Self::drain(&mut port)?;
+                    decoder.reset();
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(loaded),
+                Err(e) => return Err(e),
+            }
+        }
+
+        send(&mut port, Frame::BootSuccess)?;
+        port.write_all(&self.kernel_output)?;
+        loaded.booted = true;
+        Ok(loaded)
+    }
+
+    /// One boot attempt: handshake, then receive and check the code.
+    fn load<T: Transport>(
+        &self,
+        port: &mut LossyPort<T>,
+        decoder: &mut Decoder,
+        loaded: &mut Loaded,
+    ) -> io::Result<Result<(), Failure>> {
+        // Keep asking for the program info until something shows up.
+        port.inner.set_timeout(PROG_INFO_PERIOD)?;
+        let mut first = [0u8; 1];
+        loop {
+            send(port, Frame::GetProgInfo)?;
+            match port.read_exact(&mut first) {
+                Ok(()) => break,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        port.inner.set_timeout(PROG_INFO_TIMEOUT)?;
+
+        // A single byte never completes or breaks a header.
+        let _ = decoder.push(first[0]);
+        let header = read_header(port, decoder, BOOT_OP::PUT_PROG_INFO)?;
+        let (addr, nbytes, cksum) = match header {
+            Ok(Frame::PutProgInfo { addr, nbytes, crc }) => (addr, nbytes, crc),
+            Ok(other) => return Ok(Err((BOOT_ERR::BAD_OP, other.op().val()))),
+            Err(failure) => return Ok(Err(failure)),
+        };
+
+        loaded.addr = addr;
+        loaded.code.clear();
+
+        let end = addr as u64 + nbytes as u64;
+        if end > self.dram_end as u64 {
+            return Ok(Err((BOOT_ERR::TOO_LARGE, self.dram_end)));
+        }
+        let bootloader = self.bootloader_range();
+        if (addr as u64) < bootloader.end as u64 && end > bootloader.start as u64 {
+            return Ok(Err((BOOT_ERR::OVERLAP, bootloader.start)));
+        }
+        loaded.code.resize(nbytes as usize, 0);
+
+        port.counting = true;
+        if self.chunked {
+            send(
+                port,
+                Frame::GetCodeChunked {
+                    crc: cksum,
+                    chunk_size: self.chunk_size,
+                },
+            )?;
+            port.inner.set_timeout(CHUNK_IDLE_TIMEOUT)?;
+            if let Err(failure) = self.receive_chunks(port, decoder, loaded)? {
+                return Ok(Err(failure));
+            }
+        } else {
+            send(port, Frame::GetCode { crc: cksum })?;
+            match read_header(port, decoder, BOOT_OP::PUT_CODE)? {
+                Ok(Frame::PutCode) => {}
+                Ok(other) => return Ok(Err((BOOT_ERR::BAD_OP, other.op().val()))),
+                Err(failure) => return Ok(Err(failure)),
+            }
+            port.read_exact(&mut loaded.code)?;
+            if !self.faults.corrupt_chunks.is_empty() && !loaded.code.is_empty() {
+                loaded.code[0] ^= 1;
+            }
+        }
+
+        let recv_crc = crc32fast::hash(&loaded.code);
+        if recv_crc != cksum {
+            return Ok(Err((BOOT_ERR::CRC_MISMATCH, recv_crc)));
+        }
+        if self.faults.boot_error {
+            return Ok(Err((BOOT_ERR::CRC_MISMATCH, !cksum)));
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn drain<P: Read>(port: &mut P) -> io::Result<()> {
+        let mut byte = [0u8; 1];
+        loop {
+            match port.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) if is_timeout(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn receive_chunks<P: Read + Write>(
+        &self,
+        port: &mut P,
+        decoder: &mut Decoder,
+        loaded: &mut Loaded,
+    ) -> io::Result<Result<(), Failure>> {
+        let chunk_size = self.chunk_size as usize;
+        let n_chunks = loaded.code.len().div_ceil(chunk_size);
+        let mut corrupt = self
+            .faults
+            .corrupt_chunks
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let mut expected = 0;
+        let mut idle_naks = 0;
+
+        while expected < n_chunks {
+            let (index, len, crc) = match read_header(port, decoder, BOOT_OP::PUT_CHUNK)? {
+                Ok(Frame::PutChunk { index, nbytes, crc }) => {
+                    (index as usize, nbytes as usize, crc)
+                }
+                Err(failure) if failure.0 == BOOT_ERR::TIMEOUT => {
+                    idle_naks += 1;
+                    if idle_naks >= MAX_IDLE_NAKS {
+                        return Ok(Err(failure));
+                    }
+                    nak(port, expected, loaded)?;
+                    continue;
+                }
+                Ok(_) | Err(_) => {
+                    Self::drain(port)?;
+                    nak(port, expected, loaded)?;
+                    continue;
+                }
+            };
+            idle_naks = 0;
+
+            // The index came off the line: check it before it becomes an offset.
+            if index > expected || len != chunk_size.min(loaded.code.len() - index * chunk_size) {
+                Self::drain(port)?;
+                nak(port, expected, loaded)?;
+                continue;
+            }
+            let start = index * chunk_size;
+
+            let chunk = &mut loaded.code[start..start + len];
+            match port.read_exact(chunk) {
+                Ok(()) => {}
+                Err(e) if is_timeout(&e) => {
+                    Self::drain(port)?;
+                    nak(port, index, loaded)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            if corrupt.remove(&(index as u32)) {
+                chunk[0] ^= 1;
+            }
+
+            if crc32fast::hash(chunk) != crc {
+                Self::drain(port)?;
+                nak(port, index, loaded)?;
+                continue;
+            }
+
+            send(
+                port,
+                Frame::ChunkAck {
+                    index: index as u32,
+                    crc,
+                },
+            )?;
+            if index == expected {
+                expected += 1;
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
@@ -0,0 +1,266 @@
+//! Naming code addresses in the kernel's output.
+//!
+//! [`Symbols`] maps an address to the function containing it, using the kernel's symbol table,
+//! and to a source line when the kernel has DWARF line tables (debug builds do). [`Annotator`]
+//! finds addresses in the output and names them in place, so `pc=0x8254` reads as
+//! `pc=0x8254 <thread::t3_test_exit::trivial+0x1c at .../t3_test_exit.rs:9>`.
+//!
+//! An address is `0x` followed by up to 8 hex digits, or exactly 8 hex digits on their own (the
+//! way `{:08x}` prints it). Only addresses inside a known function are annotated.
+
+use object::elf::ELFMAG;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::rc::Rc;
+
+/// How far past its start an address may be for a function without a size, such as an
+/// assembly label.
+const UNSIZED_FUNCTION_LEN: u64 = 0x1000;
+/// Longest word that can be an address: `0x` and 8 digits.
+const MAX_ADDR_LEN: usize = 10;
+
+type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+
+struct Function {
+    addr: u64,
+    /// 0 when the symbol has no size.
+    size: u64,
+    name: String,
+}
+
+pub struct Symbols {
+    /// Sorted by address, one per address.
+    functions: Vec<Function>,
+    lines: Option<addr2line::Context<Reader>>,
+}
+
+fn invalid_data<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads the symbols of `file`, or returns `None` if it is not an ELF file.
+pub fn read_symbols(file: &Path) -> Result<Option<Symbols>, Error> {
+    let data = fs::read(file)?;
+    if !data.starts_with(&ELFMAG) {
+        return Ok(None);
+    }
+    Symbols::from_elf(&data).map(Some)
+}
+
+impl Symbols {
+    /// Symbols without line information. `functions` are `(addr, size, name)`, with a size of 0
+    /// when it is unknown; mangled names are demangled.
+    pub fn new(functions: impl IntoIterator<Item = (u64, u64, String)>) -> Symbols {
+        let mut functions = functions
+            .into_iter()
+            .map(|(addr, size, name)| Function {
+                addr,
+                size,
+                name: format!("{:#}", rustc_demangle::demangle(&name)),
+            })
+            .collect::<Vec<_>>();
+        // Aliases (`put32` and `PUT32`) share an address: keep the one with a size, if any.
+        functions.sort_by_key(|f| (f.addr, u64::MAX - f.size));
+        functions.dedup_by_key(|f| f.addr);
+
+        Symbols {
+            functions,
+            lines: None,
+        }
+    }
+
+    /// Reads the function symbols and, if present, the line tables of an ELF file.
+    pub fn from_elf(data: &[u8]) -> Result<Symbols, Error> {
+        let file = object::File::parse(data).map_err(invalid_data)?;
+
+        let in_text = |symbol: &object::Symbol| {
+            symbol
+                .section_index()
+                .and_then(|i| file.section_by_index(i).ok())
+                .is_some_and(|section| section.kind() == SectionKind::Text)
+        };
+        let functions = file
+            .symbols()
+            // Assembly labels are untyped and have no size.
+            .filter(|s| in_text(s) && matches!(s.kind(), SymbolKind::Text | SymbolKind::Unknown))
+            .filter_map(|s| {
+                let name = s.name().ok()?;
+                // `$a`, `$d` and `$t` mark ARM code, data and Thumb code, not functions.
+                if name.is_empty() || name.starts_with('$') {
+                    return None;
+                }
+                Some((s.address(), s.size(), name.to_string()))
+            });
+        let mut symbols = Symbols::new(functions);
+
+        if file.section_by_name(".debug_line").is_some() {
+            let endian = if file.is_little_endian() {
+                gimli::RunTimeEndian::Little
+            } else {
+                gimli::RunTimeEndian::Big
+            };
+            let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+                let data = file
+                    .section_by_name(id.name())
+                    .and_then(|section| section.uncompressed_data().ok())
+                    .unwrap_or(Cow::Borrowed(&[]));
+                Ok(Reader::new(Rc::from(&*data), endian))
+            })
+            .map_err(invalid_data)?;
+            symbols.lines = Some(addr2line::Context::from_dwarf(dwarf).map_err(invalid_data)?);
+        }
+
+        Ok(symbols)
+    }
+
+    /// How many functions are known.
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// The function containing `addr` and how far into it `addr` is.
+    pub fn function(&self, addr: u64) -> Option<(&str, u64)> {
+        let i = self.functions.partition_point(|f| f.addr <= addr);
+        let function = &self.functions[i.checked_sub(1)?];
+        let offset = addr - function.addr;
+        let len = match function.size {
+            0 => UNSIZED_FUNCTION_LEN,
+            size => size,
+        };
+        (offset < len).then_some((function.name.as_str(), offset))
+    }
+
+    /// The source line of `addr` as `file:line`, if the line tables cover it.
+    pub fn line(&self, addr: u64) -> Option<String> {
+        let location = self.lines.as_ref()?.find_location(addr).ok()??;
+        Some(format!("{}:{}", location.file?, location.line?))
+    }
+
+    /// The functions `addr` is running, outermost first: the function containing it, then the
+    /// ones inlined into it, if the debug info says. Empty outside every function.
+    pub fn frames(&self, addr: u64) -> Vec<String> {
+        let Some((function, _)) = self.function(addr) else {
+            return Vec::new();
+        };
+        let mut inlined = Vec::new();
+        if let Some(lines) = &self.lines
+            && let Ok(mut frames) = lines.find_frames(addr).skip_all_loads()
+        {
+            while let Ok(Some(frame)) = frames.next() {
+                let name = frame.function.as_ref().and_then(|f| f.raw_name().ok());
+                inlined.push(name.map_or("??".to_string(), |name| {
+                    format!("{:#}", rustc_demangle::demangle(&name))
+                }));
+            }
+        }
+        // The innermost frame comes first and the outermost is the function itself, which the
+        // symbol table names the same way as everywhere else.
+        inlined.pop();
+        inlined.push(function.to_string());
+        inlined.reverse();
+        inlined
+    }
+
+    /// Names `addr`: the function containing it plus the offset, then the source line if known.
+    /// Returns `None` for addresses outside every function.
+    pub fn lookup(&self, addr: u64) -> Option<String> {
+        let (function, offset) = self.function(addr)?;
+        let mut name = function.to_string();
+        if offset != 0 {
+            let _ = write!(name, "+{:#x}", offset);
+        }
+        if let Some(line) = self.line(addr) {
+            let _ = write!(name, " at {}", line);
+        }
+        Some(name)
+    }
+}
+
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// The address `word` spells, if it spells one.
+fn parse_addr(word: &[u8]) -> Option<u64> {
+    let digits = match word.strip_prefix(b"0x") {
+        Some(digits) if (1..=8).contains(&digits.len()) => digits,
+        None if word.len() == 8 => word,
+        _ => return None,
+    };
+    u64::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+/// Annotates addresses in a stream of output.
+pub struct Annotator<'a> {
+    symbols: &'a Symbols,
+    /// The end of the last text, which may be the start of an address.
+    held: Vec<u8>,
+    /// The last text ended in the middle of a word too long to be an address.
+    mid_word: bool,
+}
+
+impl<'a> Annotator<'a> {
+    pub fn new(symbols: &'a Symbols) -> Self {
+        Annotator {
+            symbols,
+            held: Vec::new(),
+            mid_word: false,
+        }
+    }
+
+    /// Annotates `text`. A word at its end that might continue into the next text is held back
+    /// until then, or until [`Annotator::flush`].
+    pub fn feed(&mut self, text: &[u8]) -> Vec<u8> {
+        self.held.extend_from_slice(text);
+        let tail = self.held.iter().rev().take_while(|&&b| is_word(b)).count();
+        let keep = if tail <= MAX_ADDR_LEN { tail } else { 0 };
+
+        let ready = self
+            .held
+            .drain(..self.held.len() - keep)
+            .collect::<Vec<_>>();
+        let out = self.annotate(&ready);
+        self.mid_word = tail > MAX_ADDR_LEN || (self.mid_word && ready.iter().all(|&b| is_word(b)));
+        out
+    }
+
+    /// Annotates whatever is held back.
+    pub fn flush(&mut self) -> Vec<u8> {
+        let held = std::mem::take(&mut self.held);
+        let out = self.annotate(&held);
+        self.mid_word = false;
+        out
+    }
+
+    fn annotate(&self, text: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(text.len());
+        let mut rest = text;
+        // The rest of a word that started in an earlier text.
+        if self.mid_word {
+            let len = rest.iter().take_while(|&&b| is_word(b)).count();
+            out.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+        }
+        while !rest.is_empty() {
+            let len = match rest.iter().position(|&b| is_word(b) != is_word(rest[0])) {
+                Some(len) => len,
+                None => rest.len(),
+            };
+            let (word, after) = rest.split_at(len);
+            out.extend_from_slice(word);
+            if let Some(name) = parse_addr(word).and_then(|addr| self.symbols.lookup(addr)) {
+                out.extend_from_slice(format!(" <{}>", name).as_bytes());
+            }
+            rest = after;
+        }
+        out
+    }
+}
@@ -0,0 +1,417 @@
+//! Talking to the kernel once it is running.
+//!
+//! [`session`] echoes everything the pi prints while a second thread forwards input to it:
+//! keystrokes in interactive mode, or the contents of a script. In interactive mode the
+//! terminal is put in raw mode so keys go out as they are typed, and [`ESCAPE`] followed by
+//! [`QUIT`] ends the session. So does the kernel sending `KERNEL_EXIT`: [`session`] returns its
+//! exit code, along with the results of any tests the kernel ran and any profile it dumped.
+
+use crate::profile::{DumpScanner, Profile};
+use crate::report::{TestReport, describe};
+use crate::symbols::{Annotator, Symbols};
+use boot_proto::{BOOT_OP, DecodeError, Frame};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Starts an escape sequence: Ctrl-A.
+pub const ESCAPE: u8 = 0x01;
+/// After [`ESCAPE`], quits the session.
+pub const QUIT: u8 = b'x';
+/// How often the output loop checks whether the session is over.
+pub const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// Looks for the escape sequence in typed input.
+///
+/// `ESCAPE QUIT` quits, `ESCAPE ESCAPE` sends a single `ESCAPE`, and `ESCAPE` followed by
+/// anything else sends both bytes.
+#[derive(Debug, Default)]
+pub struct EscapeFilter {
+    escaped: bool,
+}
+
+impl EscapeFilter {
+    /// Feeds one typed byte, appending what should go to the pi to `out`. Returns `true` once
+    /// the quit sequence is complete.
+    pub fn feed(&mut self, byte: u8, out: &mut Vec<u8>) -> bool {
+        if !self.escaped {
+            if byte == ESCAPE {
+                self.escaped = true;
+            } else {
+                out.push(byte);
+            }
+            return false;
+        }
+
+        self.escaped = false;
+        match byte {
+            QUIT => return true,
+            ESCAPE => out.push(ESCAPE),
+            _ => out.extend([ESCAPE, byte]),
+        }
+        false
+    }
+}
+
+/// The frames a kernel mixes into its text.
+const KERNEL_OPS: [BOOT_OP; 4] = [
+    BOOT_OP::KERNEL_EXIT,
+    BOOT_OP::TEST_START,
+    BOOT_OP::TEST_RESULT,
+    BOOT_OP::PROFILE_SAMPLES,
+];
+/// Longest payload the scanner waits for: a test name, or a batch of samples. A frame with a
+/// longer one is taken for text.
+pub const MAX_PAYLOAD_LEN: usize = 1024;
+
+/// Kernel output: text, or a frame (`TEST_START`, `TEST_RESULT`, `PROFILE_SAMPLES`) and its
+/// payload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Output {
+    Text(Vec<u8>),
+    Frame(Frame, Vec<u8>),
+}
+
+fn push_text(out: &mut Vec<Output>, byte: u8) {
+    match out.last_mut() {
+        Some(Output::Text(text)) => text.push(byte),
+        _ => out.push(Output::Text(vec![byte])),
+    }
+}
+
+/// What the bytes held by [`FrameScanner`] turn out to be.
+enum Held {
+    /// The first one is text.
+    Text,
+    /// Maybe the start of a frame; more bytes will tell.
+    Partial,
+    /// A frame with a header of this length, followed by its payload.
+    Frame(Frame, usize),
+}
+
+/// Picks the kernel's frames out of its output.
+///
+/// Bytes that might start a frame are held back until it is clear whether they do. Their ops
+/// read as four UTF-8 continuation bytes in a row, which no text contains.
+#[derive(Debug, Default)]
+pub struct FrameScanner {
+    held: Vec<u8>,
+}
+
+impl FrameScanner {
+    fn check(&self) -> Held {
+        let n = self.held.len().min(4);
+        if n == 0 {
+            return Held::Partial;
+        }
+        if !KERNEL_OPS
+            .iter()
+            .any(|op| op.val().to_le_bytes().starts_with(&self.held[..n]))
+        {
+            return Held::Text;
+        }
+
+        match Frame::decode(&self.held) {
+            Ok((frame, len)) => match frame.payload_len() {
+                Some(payload) if payload <= MAX_PAYLOAD_LEN => {
+                    if self.held.len() < len + payload {
+                        Held::Partial
+                    } else {
+                        Held::Frame(frame, len)
+                    }
+                }
+                _ => Held::Text,
+            },
+            Err(DecodeError::Truncated { .. }) => Held::Partial,
+            Err(_) => Held::Text,
+        }
+    }
+
+    /// Feeds kernel output, appending its text and frames to `out` in order. Returns the
+    /// exit code once the `KERNEL_EXIT` frame is complete; anything after it is dropped.
+    pub fn feed(&mut self, bytes: &[u8], out: &mut Vec<Output>) -> Option<i32> {
+        for &byte in bytes {
+            self.held.push(byte);
+            loop {
+                match self.check() {
+                    Held::Text => push_text(out, self.held.remove(0)),
+                    Held::Partial => break,
+                    Held::Frame(Frame::KernelExit { code }, _) => {
+                        self.held.clear();
+                        return Some(code as i32);
+                    }
+                    Held::Frame(frame, len) => {
+                        out.push(Output::Frame(frame, self.held.split_off(len)));
+                        self.held.clear();
+                        break;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Hands back bytes held for a frame that never came.
+    pub fn finish(&mut self, out: &mut Vec<Output>) {
+        for byte in self.held.drain(..) {
+            push_text(out, byte);
+        }
+    }
+}
+
+/// Where the input for the pi comes from.
+pub enum Input<R> {
+    /// Typed keys, filtered for the escape sequence.
+    Interactive(R),
+    /// A script, sent as is. The session goes on after it ends.
+    Script(R),
+}
+
+/// Forwards input to the pi until it ends or, in interactive mode, the user quits.
+fn forward_input<R: Read, W: Write>(
+    input: Input<R>,
+    mut port: W,
+    quit: &AtomicBool,
+) -> io::Result<()> {
+    let (mut input, interactive) = match input {
+        Input::Interactive(r) => (r, true),
+        Input::Script(r) => (r, false),
+    };
+
+    let mut filter = EscapeFilter::default();
+    let mut buf = [0u8; 256];
+    let mut out = Vec::with_capacity(buf.len());
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        out.clear();
+        let mut done = false;
+        if interactive {
+            for &byte in &buf[..n] {
+                if filter.feed(byte, &mut out) {
+                    done = true;
+                    break;
+                }
+            }
+        } else {
+            out.extend_from_slice(&buf[..n]);
+        }
+
+        port.write_all(&out)?;
+        port.flush()?;
+
+        if done {
+            quit.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+    }
+}
+
+/// What the kernel reported besides its text.
+#[derive(Default)]
+struct Recorder {
+    tests: TestReport,
+    profile: Profile,
+    dump: DumpScanner,
+}
+
+impl Recorder {
+    /// Prints kernel output to `out`, recording tests and profile samples.
+    fn show<W: Write>(&mut self, output: Output, out: &mut W) -> io::Result<()> {
+        match output {
+            Output::Text(text) => {
+                self.tests.output(&text);
+                self.dump.feed(&text, &mut self.profile);
+                out.write_all(String::from_utf8_lossy(&text).as_bytes())?;
+            }
+            Output::Frame(Frame::TestStart { index, total, .. }, name) => {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                writeln!(out, "running {}", name)?;
+                self.tests.start(index, total, name);
+            }
+            Output::Frame(
+                Frame::TestResult {
+                    index,
+                    status,
+                    usec,
+                },
+                _,
+            ) => match self.tests.finish(index, status, usec) {
+                Some(case) => writeln!(
+                    out,
+                    "test {} ... {} ({} ms)",
+                    case.name,
+                    describe(case.status),
+                    usec / 1000
+                )?,
+                None => writeln!(out, "result for test {}, which never started", index)?,
+            },
+            Output::Frame(Frame::ProfileSamples { period_usec, .. }, samples) => {
+                self.profile.add_frame(period_usec, &samples);
+            }
+            // The scanner hands out no other frames.
+            Output::Frame(..) => {}
+        }
+        out.flush()
+    }
+}
+
+/// Copies pi output to `out` until `quit` is set, the port hangs up or the kernel exits.
+/// `port` should time out after about [`POLL_PERIOD`] so the flag is noticed.
+fn echo_output<R: Read, W: Write>(
+    port: &mut R,
+    out: &mut W,
+    quit: &AtomicBool,
+    recorder: &mut Recorder,
+    symbols: Option<&Symbols>,
+) -> io::Result<Option<i32>> {
+    let mut scanner = FrameScanner::default();
+    let mut annotator = symbols.map(Annotator::new);
+    let mut read_buf = [0u8; 1024];
+    let mut outputs = Vec::new();
+    let mut exit_code = None;
+
+    while !quit.load(Ordering::Relaxed) && exit_code.is_none() {
+        // Whether text held back for an address can go out now.
+        let idle = match port.read(&mut read_buf) {
+            Ok(0) => {
+                scanner.finish(&mut outputs);
+                quit.store(true, Ordering::Relaxed);
+                true
+            }
+            Ok(n) => {
+                exit_code = scanner.feed(&read_buf[..n], &mut outputs);
+                exit_code.is_some()
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => true,
+            Err(e) => return Err(e),
+        };
+
+        for output in outputs.drain(..) {
+            let Some(annotator) = annotator.as_mut() else {
+                recorder.show(output, out)?;
+                continue;
+            };
+            match output {
+                Output::Text(text) => recorder.show(Output::Text(annotator.feed(&text)), out)?,
+                frame => {
+                    recorder.show(Output::Text(annotator.flush()), out)?;
+                    recorder.show(frame, out)?;
+                }
+            }
+        }
+        if idle && let Some(annotator) = annotator.as_mut() {
+            recorder.show(Output::Text(annotator.flush()), out)?;
+        }
+    }
+    Ok(exit_code)
+}
+
+/// How a [`session`] ended.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// The kernel's exit code, if it sent one.
+    pub exit_code: Option<i32>,
+    /// Tests the kernel ran, if it was built by `cargo test`.
+    pub tests: TestReport,
+    /// PC samples the kernel dumped, as frames or as text.
+    pub profile: Profile,
+}
+
+/// Runs a session with the booted kernel: `rx` and `tx` are the two halves of the port, `out`
+/// is where pi output goes. With `symbols`, code addresses in the output are named.
+///
+/// The session ends when the kernel exits, with its exit code, or when the port hangs up. In
+/// interactive mode it also ends when the user quits. Input is read on its own thread, which
+/// is left behind if the session ends while it waits for a key.
+pub fn session<R, W, I, O>(
+    rx: &mut R,
+    tx: W,
+    input: Option<Input<I>>,
+    out: &mut O,
+    symbols: Option<&Symbols>,
+) -> io::Result<Outcome>
+where
+    R: Read,
+    W: Write + Send + 'static,
+    I: Read + Send + 'static,
+    O: Write,
+{
+    let quit = Arc::new(AtomicBool::new(false));
+
+    let forward = input.map(|input| {
+        let quit = quit.clone();
+        thread::spawn(move || forward_input(input, tx, &quit))
+    });
+
+    let mut recorder = Recorder::default();
+    let echoed = echo_output(rx, out, &quit, &mut recorder, symbols);
+    quit.store(true, Ordering::Relaxed);
+
+    let forwarded = match forward {
+        Some(handle) if handle.is_finished() => handle.join().unwrap(),
+        _ => Ok(()),
+    };
+    let exit_code = echoed?;
+    let Recorder {
+        tests,
+        mut profile,
+        mut dump,
+    } = recorder;
+    dump.finish(&mut profile);
+    forwarded.map(|_| Outcome {
+        exit_code,
+        tests,
+        profile,
+    })
+}
+
+/// Puts a terminal in raw mode until dropped.
+///
+/// Keys are passed through one at a time and unechoed, and Ctrl-C and friends go to the pi
+/// instead of killing `pi-install`. Enter still sends `\n`, and output processing is left on so
+/// the pi's `\n` starts a new line.
+#[cfg(unix)]
+pub struct RawMode {
+    fd: i32,
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    pub fn enable(fd: i32) -> io::Result<RawMode> {
+        unsafe {
+            let mut saved = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = saved;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::BRKINT | libc::ISTRIP);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(fd, libc::TCSAFLUSH, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(RawMode { fd, saved })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.saved);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::image::Image;
+use crate::{boot_error, read_frame, read_string, write_frame};
+use boot_proto::{Decoder, Frame};
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How many times a single chunk is resent before giving up on the transfer.
+const MAX_RETRIES: u32 = 16;
+
+/// Statistics of a finished chunked transfer.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub chunks: usize,
+    pub retransmits: u32,
+    pub elapsed: Duration,
+}
+
+fn print_progress(done: usize, total: usize, sent_bytes: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64().max(1e-6);
+    print!(
+        "\rSending code: {}/{} chunks, {:.1} KiB/s",
+        done,
+        total,
+        sent_bytes as f64 / 1024.0 / secs
+    );
+    let _ = io::stdout().flush();
+}
+
+/// Sends the code of `image` as `PUT_CHUNK` packets of `chunk_size` bytes, waiting for a `CHUNK_ACK`
+/// after each one.
+///
+/// A `CHUNK_NAK` rewinds to the chunk the pi asks for, and a chunk that is not acked in time
+/// (the port's read timeout) is resent. Acks for chunks other than the current one are stale
+/// duplicates and are ignored.
+pub fn send_chunked<P: Read + Write>(
+    port: &mut P,
+    decoder: &mut Decoder,
+    image: &Image,
+    chunk_size: usize,
+) -> io::Result<TransferStats> {
+    let chunks = image.code.chunks(chunk_size).collect::<Vec<_>>();
+    let start = Instant::now();
+    let mut retransmits = 0;
+    let mut retries = 0;
+    let mut sent_bytes = 0;
+
+    let mut index = 0;
+    while index < chunks.len() {
+        if retries > MAX_RETRIES {
+            return Err(io::Error::other(format!(
+                "chunk {} failed after {} retries",
+                index, MAX_RETRIES
+            )));
+        }
+
+        let chunk = chunks[index];
+        let put_chunk = Frame::PutChunk {
+            index: index as u32,
+            nbytes: chunk.len() as u32,
+            crc: crc32fast::hash(chunk),
+        };
+        write_frame(port, put_chunk, chunk)?;
+        sent_bytes += chunk.len();
+
+        // Wait for the reply to this chunk.
+        loop {
+            let frame = match read_frame(port, decoder) {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    decoder.reset();
+                    retries += 1;
+                    retransmits += 1;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+
+            match frame {
+                Frame::ChunkAck { index: acked, crc } => {
+                    if acked as usize != index {
+                        continue;
+                    }
+                    if crc != crc32fast::hash(chunks[index]) {
+                        // The pi stored something else: sending the chunk again overwrites it.
+                        retries += 1;
+                        retransmits += 1;
+                        break;
+                    }
+
+                    index += 1;
+                    retries = 0;
+                    print_progress(index, chunks.len(), sent_bytes, start);
+                    break;
+                }
+                Frame::ChunkNak { index: wanted } => {
+                    let wanted = wanted as usize;
+                    if wanted >= chunks.len() {
+                        return Err(io::Error::other(format!(
+                            "pi asked for chunk {} of {}",
+                            wanted,
+                            chunks.len()
+                        )));
+                    }
+
+                    index = wanted;
+                    retries += 1;
+                    retransmits += 1;
+                    break;
+                }
+                Frame::PrintString { len } => {
+                    println!("\nPI: {}", read_string(port, len)?);
+                }
+                Frame::BootError { code, detail } => {
+                    println!();
+                    return Err(boot_error(code, detail, image));
+                }
+                x => println!("\nUnexpected OP during transfer: {:?}", x.op()),
+            }
+        }
+    }
+    println!();
+
+    Ok(TransferStats {
+        chunks: chunks.len(),
+        retransmits,
+        elapsed: start.elapsed(),
+    })
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A byte stream to the pi: the serial port, or an in-memory [`pipe`] in tests.
+///
+/// Reads must fail with [`ErrorKind::TimedOut`] once the timeout passes without data.
+pub trait Transport: Read + Write + Send {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn serialport::SerialPort> {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        serialport::SerialPort::set_timeout(self.as_mut(), timeout)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One end of an in-memory, full duplex [`Transport`].
+pub struct PipeEnd {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+    timeout: Duration,
+}
+
+/// Creates two connected [`PipeEnd`]s: bytes written to one can be read from the other.
+///
+/// Dropping an end makes reads on the other one return EOF once the buffered bytes are gone.
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let a = Arc::new(Channel::default());
+    let b = Arc::new(Channel::default());
+    let timeout = Duration::from_secs(5);
+
+    (
+        PipeEnd {
+            rx: a.clone(),
+            tx: b.clone(),
+            timeout,
+        },
+        PipeEnd {
+            rx: b,
+            tx: a,
+            timeout,
+        },
+    )
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.rx.state.lock().unwrap();
+        while state.buf.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "pipe read timed out"));
+            }
+            state = self.rx.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        let n = out.len().min(state.buf.len());
+        for (dst, src) in out.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        state.buf.extend(data);
+        self.tx.cond.notify_all();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        self.tx.state.lock().unwrap().closed = true;
+        self.tx.cond.notify_all();
+    }
+}
+
+impl Transport for PipeEnd {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use boot_proto::{BOOT_ERR, BOOT_OP, Frame};
+use constants::ARM_BASE;
+use pi_install::image::Image;
+use pi_install::sim::{FakeBootloader, Faults};
+use pi_install::terminal::MAX_PAYLOAD_LEN;
+use pi_install::{boot, diagnose, read_frame, read_string, write_frame};
+use std::io::{ErrorKind, Read, Write};
+
+fn test_image(nbytes: usize) -> Image {
+    Image {
+        addr: ARM_BASE,
+        entry: ARM_BASE,
+        code: (0..nbytes).map(|i| (i * 7 + i / 251) as u8).collect(),
+    }
+}
+
+fn faulty(faults: Faults) -> FakeBootloader {
+    FakeBootloader {
+        faults,
+        ..FakeBootloader::default()
+    }
+}
+
+#[test]
+fn chunked_boot_delivers_image_and_output() {
+    let image = test_image(5000);
+    let fake = FakeBootloader {
+        kernel_output: b"hello from the pi\n".to_vec(),
+        ..FakeBootloader::default()
+    };
+
+    let (mut port, pi) = fake.spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.addr, ARM_BASE);
+    assert_eq!(loaded.code, image.code);
+    assert_eq!(loaded.naks, 0);
+
+    let mut output = String::new();
+    port.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "hello from the pi\n");
+}
+
+#[test]
+fn single_shot_boot_still_works() {
+    let image = test_image(3000);
+    let fake = FakeBootloader {
+        chunked: false,
+        ..FakeBootloader::default()
+    };
+
+    let (mut port, pi) = fake.spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, image.code);
+}
+
+#[test]
+fn image_smaller_than_a_chunk() {
+    let image = test_image(13);
+
+    let (mut port, pi) = FakeBootloader::default().spawn();
+    boot(&mut port, &image).unwrap();
+
+    assert_eq!(pi.join().unwrap().unwrap().code, image.code);
+}
+
+#[test]
+fn dropped_byte_is_retransmitted() {
+    let image = test_image(4096);
+    let fake = faulty(Faults {
+        // One byte in the middle of the second chunk's data.
+        drop_bytes: vec![1024 + 16 + 16 + 100],
+        ..Faults::default()
+    });
+
+    let (mut port, pi) = fake.spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, image.code);
+    assert!(loaded.naks >= 1);
+}
+
+#[test]
+fn dropped_header_byte_is_retransmitted() {
+    let image = test_image(2048);
+    let fake = faulty(Faults {
+        drop_bytes: vec![0],
+        ..Faults::default()
+    });
+
+    let (mut port, pi) = fake.spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, image.code);
+}
+
+#[test]
+fn bad_crc_chunks_are_retransmitted() {
+    let image = test_image(4500);
+    let fake = faulty(Faults {
+        corrupt_chunks: vec![0, 2, 4],
+        ..Faults::default()
+    });
+
+    let (mut port, pi) = fake.spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, image.code);
+    assert_eq!(loaded.naks, 3);
+}
+
+#[test]
+fn boot_error_is_reported() {
+    let image = test_image(1000);
+    let fake = faulty(Faults {
+        boot_error: true,
+        ..Faults::default()
+    });
+
+    let (mut port, pi) = fake.spawn();
+    let err = boot(&mut port, &image).unwrap_err();
+    assert!(err.to_string().contains("BOOT_ERROR"), "{}", err);
+    assert!(err.to_string().contains("crc32"), "{}", err);
+    drop(port);
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(!loaded.booted);
+    assert_eq!(loaded.errors[0].0, BOOT_ERR::CRC_MISMATCH);
+}
+
+#[test]
+fn corrupted_single_shot_image_is_reported() {
+    let image = test_image(1000);
+    let fake = FakeBootloader {
+        chunked: false,
+        faults: Faults {
+            corrupt_chunks: vec![0],
+            ..Faults::default()
+        },
+        ..FakeBootloader::default()
+    };
+
+    let (mut port, pi) = fake.spawn();
+    assert!(boot(&mut port, &image).is_err());
+    drop(port);
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(!loaded.booted);
+    assert_eq!(
+        loaded.errors,
+        [(BOOT_ERR::CRC_MISMATCH, crc32fast::hash(&loaded.code))]
+    );
+}
+
+#[test]
+fn image_colliding_with_bootloader_is_rejected() {
+    let fake = FakeBootloader::default();
+    let bootloader = fake.bootloader_range();
+    assert_eq!(bootloader.end, fake.dram_end);
+    let mut image = test_image(16);
+    image.addr = bootloader.start - 8;
+
+    let (mut port, pi) = fake.spawn();
+    let err = boot(&mut port, &image).unwrap_err();
+    assert!(
+        err.to_string().contains(&format!(
+            "runs into the bootloader at {:#x}",
+            bootloader.start
+        )),
+        "{}",
+        err
+    );
+    drop(port);
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(!loaded.booted);
+    assert_eq!(loaded.errors, [(BOOT_ERR::OVERLAP, bootloader.start)]);
+}
+
+#[test]
+fn bootloader_moves_with_the_dram_size() {
+    // A 256MB board with half of it for the GPU.
+    let fake = FakeBootloader {
+        dram_end: 0x08000000,
+        ..FakeBootloader::default()
+    };
+    let bootloader = fake.bootloader_range();
+    assert!(bootloader.end <= 0x08000000 && bootloader.start < 0x08000000);
+
+    let mut image = test_image(64);
+    image.addr = bootloader.start - 32;
+    let (mut port, pi) = fake.spawn();
+    assert!(boot(&mut port, &image).is_err());
+    drop(port);
+    assert_eq!(
+        pi.join().unwrap().unwrap().errors,
+        [(BOOT_ERR::OVERLAP, bootloader.start)]
+    );
+}
+
+#[test]
+fn image_past_the_end_of_dram_is_rejected() {
+    let fake = FakeBootloader::default();
+    let dram_end = fake.dram_end;
+    let mut image = test_image(64);
+    image.addr = dram_end - 32;
+
+    let (mut port, pi) = fake.spawn();
+    let err = boot(&mut port, &image).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("does not fit in the pi's 0x1c000000 bytes of DRAM"),
+        "{}",
+        err
+    );
+    drop(port);
+
+    assert_eq!(
+        pi.join().unwrap().unwrap().errors,
+        [(BOOT_ERR::TOO_LARGE, dram_end)]
+    );
+}
+
+#[test]
+fn image_larger_than_2mb_boots() {
+    let image = test_image(3 << 20);
+
+    let (mut port, pi) = FakeBootloader::default().spawn();
+    boot(&mut port, &image).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, image.code);
+}
+
+#[test]
+fn image_across_256mb_boots() {
+    let mut image = test_image(2000);
+    image.addr = 0x10000000 - 1000;
+
+    let (mut port, pi) = FakeBootloader::default().spawn();
+    boot(&mut port, &image).unwrap();
+
+    assert!(pi.join().unwrap().unwrap().booted);
+}
+
+#[test]
+fn chunk_with_a_huge_index_is_naked() {
+    let (mut port, pi) = FakeBootloader::default().spawn();
+
+    let mut decoder = Default::default();
+    assert_eq!(
+        read_frame(&mut port, &mut decoder).unwrap(),
+        Frame::GetProgInfo
+    );
+    let code = [0u8; 16];
+    let crc = crc32fast::hash(&code);
+    write_frame(
+        &mut port,
+        Frame::PutProgInfo {
+            addr: ARM_BASE,
+            nbytes: code.len() as u32,
+            crc,
+        },
+        &[],
+    )
+    .unwrap();
+    let frame = loop {
+        match read_frame(&mut port, &mut decoder).unwrap() {
+            Frame::GetProgInfo => continue,
+            frame => break frame,
+        }
+    };
+    assert!(matches!(frame, Frame::GetCodeChunked { .. }), "{:?}", frame);
+
+    write_frame(
+        &mut port,
+        Frame::PutChunk {
+            index: u32::MAX,
+            nbytes: code.len() as u32,
+            crc,
+        },
+        &code,
+    )
+    .unwrap();
+    assert_eq!(
+        read_frame(&mut port, &mut decoder).unwrap(),
+        Frame::ChunkNak { index: 0 }
+    );
+    drop(port);
+    assert!(!pi.join().unwrap().unwrap().booted);
+}
+
+#[test]
+fn strings_longer_than_a_payload_are_refused() {
+    let text = vec![b'x'; MAX_PAYLOAD_LEN + 1];
+    assert_eq!(
+        read_string(&mut &text[..], MAX_PAYLOAD_LEN as u32).unwrap(),
+        "x".repeat(MAX_PAYLOAD_LEN)
+    );
+    let err = read_string(&mut &text[..], u32::MAX).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn garbage_instead_of_prog_info_is_a_bad_op() {
+    let (mut port, pi) = FakeBootloader::default().spawn();
+
+    let mut decoder = Default::default();
+    assert_eq!(
+        read_frame(&mut port, &mut decoder).unwrap(),
+        Frame::GetProgInfo
+    );
+    port.write_all(&[0x12, 0x34, 0x56, 0x78]).unwrap();
+
+    let frame = loop {
+        match read_frame(&mut port, &mut decoder).unwrap() {
+            Frame::GetProgInfo => continue,
+            frame => break frame,
+        }
+    };
+    assert_eq!(
+        frame,
+        Frame::BootError {
+            code: BOOT_ERR::BAD_OP.val(),
+            detail: 0x78563412
+        }
+    );
+    drop(port);
+    assert!(!pi.join().unwrap().unwrap().booted);
+}
+
+#[test]
+fn bootloader_resyncs_after_an_error() {
+    let fake = FakeBootloader::default();
+    let bootloader = fake.bootloader_range();
+    let mut bad = test_image(64);
+    bad.addr = bootloader.start - 8;
+    let good = test_image(3000);
+
+    let (mut port, pi) = fake.spawn();
+    assert!(boot(&mut port, &bad).is_err());
+    boot(&mut port, &good).unwrap();
+
+    let loaded = pi.join().unwrap().unwrap();
+    assert!(loaded.booted);
+    assert_eq!(loaded.code, good.code);
+    assert_eq!(loaded.errors, [(BOOT_ERR::OVERLAP, bootloader.start)]);
+}
+
+#[test]
+fn every_error_code_has_a_diagnosis() {
+    let image = test_image(100);
+    for (code, detail, expected) in [
+        (
+            BOOT_ERR::BAD_OP,
+            0x12345678,
+            "0x12345678, which is not a BOOT_OP",
+        ),
+        (
+            BOOT_ERR::TOO_LARGE,
+            0x8010,
+            "0x8000..0x8064 does not fit in the pi's 0x8010 bytes",
+        ),
+        (
+            BOOT_ERR::OVERLAP,
+            0x8010,
+            "0x8000..0x8064 runs into the bootloader at 0x8010",
+        ),
+        (BOOT_ERR::CRC_MISMATCH, 0xabcd, "crc32 abcd"),
+        (
+            BOOT_ERR::TIMEOUT,
+            BOOT_OP::PUT_CHUNK.val(),
+            "waiting for PUT_CHUNK",
+        ),
+    ] {
+        let text = diagnose(code.val(), detail, &image);
+        assert!(text.contains(expected), "{}", text);
+    }
+    assert!(diagnose(99, 0, &image).contains("unknown error code 99"));
+}
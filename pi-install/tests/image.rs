@@ -0,0 +1,147 @@
+use pi_install::image::{Image, read_image};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// A `PT_LOAD` program header: `data` goes at `paddr`, zero filled up to `memsz`.
+struct Load<'a> {
+    paddr: u32,
+    memsz: u32,
+    data: &'a [u8],
+}
+
+/// A little-endian 32-bit ARM executable with just an ELF header and program headers.
+fn elf(entry: u32, loads: &[Load]) -> Vec<u8> {
+    const EHSIZE: u32 = 52;
+    const PHENTSIZE: u32 = 32;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+    out.resize(16, 0);
+    for half in [2u16, 40] {
+        out.extend_from_slice(&half.to_le_bytes()); // e_type = EXEC, e_machine = ARM
+    }
+    for word in [1, entry, EHSIZE, 0, 0] {
+        out.extend_from_slice(&word.to_le_bytes()); // e_version .. e_flags
+    }
+    let phnum = loads.len() as u16;
+    for half in [EHSIZE as u16, PHENTSIZE as u16, phnum, 40, 0, 0] {
+        out.extend_from_slice(&half.to_le_bytes()); // e_ehsize .. e_shstrndx
+    }
+
+    let mut offset = EHSIZE + PHENTSIZE * loads.len() as u32;
+    for load in loads {
+        // The virtual address is deliberately not the physical one: only p_paddr counts.
+        let vaddr = load.paddr + 0x8000_0000;
+        let filesz = load.data.len() as u32;
+        for word in [1, offset, vaddr, load.paddr, filesz, load.memsz, 5, 4] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        offset += filesz;
+    }
+    for load in loads {
+        out.extend_from_slice(load.data);
+    }
+    out
+}
+
+/// Writes `data` to a file of its own and reads it back as an image.
+fn read(name: &str, data: &[u8], raw_addr: u32) -> std::io::Result<Image> {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("pi-install-image-{}-{}", std::process::id(), name));
+    std::fs::write(&path, data).unwrap();
+    let image = read_image(&path, raw_addr);
+    std::fs::remove_file(&path).unwrap();
+    image
+}
+
+#[test]
+fn segments_are_laid_out_by_physical_address() {
+    let data = elf(
+        0x8000,
+        &[
+            Load {
+                paddr: 0x8008,
+                memsz: 4,
+                data: &[5, 6, 7, 8],
+            },
+            Load {
+                paddr: 0x8000,
+                memsz: 8,
+                data: &[1, 2, 3, 4, 0xa, 0xb, 0xc, 0xd],
+            },
+        ],
+    );
+    let image = read("sorted", &data, 0).unwrap();
+    assert_eq!((image.addr, image.entry), (0x8000, 0x8000));
+    assert_eq!(image.code, [1, 2, 3, 4, 0xa, 0xb, 0xc, 0xd, 5, 6, 7, 8]);
+}
+
+#[test]
+fn gaps_and_bss_are_zero_filled() {
+    let data = elf(
+        0x8000,
+        &[
+            // 4 bytes of .bss after the data, then a gap of 8 up to the next segment.
+            Load {
+                paddr: 0x8000,
+                memsz: 8,
+                data: &[1, 2, 3, 4],
+            },
+            Load {
+                paddr: 0x8010,
+                memsz: 1,
+                data: &[5],
+            },
+        ],
+    );
+    let image = read("zeros", &data, 0).unwrap();
+    let mut expected = vec![0; 0x11];
+    expected[..4].copy_from_slice(&[1, 2, 3, 4]);
+    expected[0x10] = 5;
+    assert_eq!(image.code, expected);
+}
+
+#[test]
+fn overlapping_segments_are_rejected() {
+    let data = elf(
+        0x8000,
+        &[
+            Load {
+                paddr: 0x8000,
+                memsz: 0x10,
+                data: &[1; 4],
+            },
+            Load {
+                paddr: 0x8008,
+                memsz: 4,
+                data: &[2; 4],
+            },
+        ],
+    );
+    let err = read("overlap", &data, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("overlap"), "{}", err);
+}
+
+#[test]
+fn entry_other_than_the_load_address_is_rejected() {
+    let data = elf(
+        0x8004,
+        &[Load {
+            paddr: 0x8000,
+            memsz: 8,
+            data: &[0; 8],
+        }],
+    );
+    let err = read("entry", &data, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("entry point 0x8004"), "{}", err);
+}
+
+#[test]
+fn anything_else_is_a_raw_binary() {
+    let data = [0xe3, 0xa0, 0xd3, 0x02, 0x7f, b'E', b'L', b'F'];
+    let image = read("raw", &data, 0x10000).unwrap();
+    assert_eq!((image.addr, image.entry), (0x10000, 0x10000));
+    assert_eq!(image.code, data);
+}
@@ -0,0 +1,101 @@
+use pi_install::profile::{DumpScanner, Profile, parse_sample};
+use pi_install::symbols::Symbols;
+
+fn kernel() -> Symbols {
+    Symbols::new([
+        (0x8000, 0x20, "_start".to_string()),
+        (
+            0x8254,
+            0x40,
+            "_ZN5gprof11__user_main17h0123456789abcdefE".to_string(),
+        ),
+        (0x8294, 0x100, "timer_get_usec".to_string()),
+    ])
+}
+
+fn profile(samples: &[(u64, u64)]) -> Profile {
+    let mut profile = Profile::default();
+    for &(addr, count) in samples {
+        profile.add(addr, count);
+    }
+    profile
+}
+
+#[test]
+fn dump_lines_are_samples() {
+    assert_eq!(parse_sample("00008254: 12"), Some((0x8254, 12)));
+    assert_eq!(parse_sample("00008254: 12\r"), Some((0x8254, 12)));
+    assert_eq!(
+        parse_sample("00008254 <gprof::__user_main at src/main.rs:9>: 3"),
+        Some((0x8254, 3))
+    );
+
+    assert_eq!(parse_sample("code size: 1234"), None);
+    assert_eq!(parse_sample("00008254: twelve"), None);
+    assert_eq!(parse_sample("0008254: 12"), None);
+    assert_eq!(parse_sample("00008254 and more: 12"), None);
+    assert_eq!(parse_sample("00008254:"), None);
+}
+
+#[test]
+fn samples_are_picked_out_of_text_split_anywhere() {
+    let text = b"code size: 9000\n00008000: 1\n00008258: 2\n00008294 <timer_get_usec>: 3\ndone";
+    for split in 0..text.len() {
+        let mut dump = DumpScanner::default();
+        let mut profile = Profile::default();
+        dump.feed(&text[..split], &mut profile);
+        dump.feed(&text[split..], &mut profile);
+        dump.finish(&mut profile);
+        assert_eq!(profile.total(), 6, "split at {}", split);
+    }
+}
+
+#[test]
+fn frames_add_samples_and_the_period() {
+    let mut profile = Profile::default();
+    let payload = [0x8258u32, 5, 0x8294, 3]
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect::<Vec<_>>();
+    profile.add_frame(100, &payload);
+    profile.add_frame(100, &payload[..8]);
+    assert_eq!(profile.total(), 13);
+    assert!(
+        profile
+            .report(&kernel(), 1)
+            .starts_with("13 samples at 2 addresses, one every 100 usec, 1.3 ms in all\n")
+    );
+}
+
+#[test]
+fn functions_and_lines_are_ranked() {
+    let samples = profile(&[
+        (0x8258, 5),
+        (0x825c, 2),
+        (0x8294, 3),
+        (0x8004, 1),
+        (0x9000, 1),
+    ]);
+    let report = samples.report(&kernel(), 2);
+    assert_eq!(
+        report,
+        "12 samples at 5 addresses\n\
+         \n  %       samples  function\n \
+         58.33%         7  gprof::__user_main\n \
+         25.00%         3  timer_get_usec\n  \
+         ... 2 more\n\
+         \n  %       samples  line\n \
+         41.67%         5  0x8258 (gprof::__user_main)\n \
+         25.00%         3  0x8294 (timer_get_usec)\n  \
+         ... 3 more\n"
+    );
+}
+
+#[test]
+fn folded_stacks_sum_by_function() {
+    let samples = profile(&[(0x8258, 5), (0x825c, 2), (0x8294, 3), (0x9000, 1)]);
+    assert_eq!(
+        samples.folded(&kernel()),
+        "[unknown] 1\ngprof::__user_main 7\ntimer_get_usec 3\n"
+    );
+}
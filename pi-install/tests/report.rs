@@ -0,0 +1,68 @@
+use boot_proto::TEST_STATUS;
+use pi_install::report::{Status, TestReport};
+
+fn report() -> TestReport {
+    let mut report = TestReport::default();
+    report.start(0, 4, "thread::t3_test_exit::t3_test_exit".into());
+    report.output(b"SUCCESS\n");
+    report.finish(0, TEST_STATUS::PASS.val(), 1500);
+    report.start(1, 4, "thread::t5::t5".into());
+    report.output(b"assertion `left == right` failed: <1> & \"2\"\n");
+    report.finish(1, TEST_STATUS::FAIL.val(), 20);
+    report.start(2, 4, "thread::t7::t7".into());
+    report.output(b"still going\x07");
+    report
+}
+
+#[test]
+fn unfinished_and_unstarted_tests_count_against_the_run() {
+    let report = report();
+    assert_eq!(report.cases[2].status, Status::NoResult);
+    assert_eq!(report.not_run(), 1);
+    assert!(!report.passed());
+
+    assert_eq!(
+        report.summary(),
+        "    thread::t5::t5 (FAILED)\n    thread::t7::t7 (no result)\n\
+         test result: FAILED. 1 passed; 1 failed; 0 timed out; 1 no result; 1 not run"
+    );
+}
+
+#[test]
+fn output_after_a_result_is_not_the_test_s() {
+    let mut report = TestReport::default();
+    report.start(0, 1, "a::b".into());
+    report.finish(0, TEST_STATUS::PASS.val(), 1);
+    report.output(b"1 of 1 tests failed\n");
+    assert!(report.cases[0].output.is_empty());
+    assert!(report.passed());
+    assert!(report.summary().starts_with("test result: ok. 1 passed;"));
+}
+
+#[test]
+fn junit_lists_every_test_with_its_output() {
+    let xml = report().junit("thread");
+
+    assert_eq!(
+        xml,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="thread" tests="4" failures="1" errors="1" skipped="1" time="0.001520">
+    <testcase classname="thread::t3_test_exit" name="t3_test_exit" time="0.001500">
+      <system-out>SUCCESS
+</system-out>
+    </testcase>
+    <testcase classname="thread::t5" name="t5" time="0.000020">
+      <failure type="panic" message="panicked"/>
+      <system-out>assertion `left == right` failed: &lt;1&gt; &amp; &quot;2&quot;
+</system-out>
+    </testcase>
+    <testcase classname="thread::t7" name="t7" time="0.000000">
+      <error type="no-result" message="the kernel stopped during the test"/>
+      <system-out>still going</system-out>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+    );
+}
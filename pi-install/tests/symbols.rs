@@ -0,0 +1,95 @@
+use object::{Object, ObjectSymbol, SymbolKind};
+use pi_install::symbols::{Annotator, Symbols};
+
+fn kernel() -> Symbols {
+    Symbols::new([
+        (0x8000, 0, "_start".to_string()),
+        (
+            0x8254,
+            0x40,
+            "_ZN6thread12t3_test_exit7trivial17h0123456789abcdefE".to_string(),
+        ),
+        (0x8294, 0x100, "rpi_exit".to_string()),
+    ])
+}
+
+fn annotate(symbols: &Symbols, reads: &[&[u8]]) -> String {
+    let mut annotator = Annotator::new(symbols);
+    let mut out = Vec::new();
+    for read in reads {
+        out.extend(annotator.feed(read));
+    }
+    out.extend(annotator.flush());
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn addresses_are_named_with_function_and_offset() {
+    let symbols = kernel();
+    assert_eq!(
+        symbols.lookup(0x8254).as_deref(),
+        Some("thread::t3_test_exit::trivial")
+    );
+    assert_eq!(
+        symbols.lookup(0x8260).as_deref(),
+        Some("thread::t3_test_exit::trivial+0xc")
+    );
+    assert_eq!(symbols.lookup(0x8008).as_deref(), Some("_start+0x8"));
+    assert_eq!(symbols.lookup(0x8394), None);
+    assert_eq!(symbols.lookup(0x7ffc), None);
+}
+
+#[test]
+fn addresses_in_output_are_annotated_in_place() {
+    let text = "data abort at pc=0x8260, lr=00008298\n00009000: 3\n";
+    assert_eq!(
+        annotate(&kernel(), &[text.as_bytes()]),
+        "data abort at pc=0x8260 <thread::t3_test_exit::trivial+0xc>, \
+         lr=00008298 <rpi_exit+0x4>\n00009000: 3\n"
+    );
+}
+
+#[test]
+fn only_whole_words_are_addresses() {
+    let text = "x8260 0x82600 8260 000082600 crc=deadbeef_00008260 0X8260";
+    assert_eq!(annotate(&kernel(), &[text.as_bytes()]), text);
+}
+
+#[test]
+fn addresses_split_across_reads_are_annotated() {
+    let text = b"lr=0x8298 and a_very_long_identifier_0x8298 then 00008254.";
+    let whole = annotate(&kernel(), &[text]);
+    assert!(whole.contains("lr=0x8298 <rpi_exit+0x4>"));
+    assert!(whole.contains("00008254 <thread::t3_test_exit::trivial>."));
+
+    for split in 0..text.len() {
+        let (a, b) = text.split_at(split);
+        assert_eq!(annotate(&kernel(), &[a, b]), whole, "split at {}", split);
+    }
+}
+
+/// This test's own address, read from the test binary, has to come back with this file's name.
+#[cfg(target_os = "linux")]
+#[test]
+fn lines_come_from_dwarf() {
+    let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    let file = object::File::parse(&*data).unwrap();
+    let addr = file
+        .symbols()
+        .find(|s| {
+            s.kind() == SymbolKind::Text
+                && s.name().is_ok_and(|n| {
+                    format!("{:#}", rustc_demangle::demangle(n)) == "symbols::lines_come_from_dwarf"
+                })
+        })
+        .unwrap()
+        .address();
+
+    let name = Symbols::from_elf(&data).unwrap().lookup(addr).unwrap();
+    assert!(
+        name.starts_with("symbols::lines_come_from_dwarf at "),
+        "{}",
+        name
+    );
+    assert!(name.contains("tests/symbols.rs:"), "{}", name);
+}
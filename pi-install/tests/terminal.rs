@@ -0,0 +1,326 @@
+use boot_proto::{Frame, TEST_STATUS};
+use pi_install::report::Status;
+use pi_install::terminal::{
+    ESCAPE, EscapeFilter, FrameScanner, Input, MAX_PAYLOAD_LEN, Output, QUIT, session,
+};
+use pi_install::transport::{Transport, pipe};
+use std::io::{Cursor, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+fn filter(typed: &[u8]) -> (Vec<u8>, bool) {
+    let mut filter = EscapeFilter::default();
+    let mut out = Vec::new();
+    for &byte in typed {
+        if filter.feed(byte, &mut out) {
+            return (out, true);
+        }
+    }
+    (out, false)
+}
+
+#[test]
+fn escape_sequence_quits() {
+    assert_eq!(filter(b"ls\n\x01x"), (b"ls\n".to_vec(), true));
+    assert_eq!(filter(&[ESCAPE, QUIT, b'a']), (vec![], true));
+}
+
+#[test]
+fn escaped_bytes_pass_through() {
+    assert_eq!(filter(&[ESCAPE, ESCAPE]), (vec![ESCAPE], false));
+    assert_eq!(
+        filter(&[ESCAPE, b'a', b'x']),
+        (vec![ESCAPE, b'a', b'x'], false)
+    );
+    assert_eq!(filter(&[0x03, b'q']), (vec![0x03, b'q'], false));
+}
+
+#[test]
+fn interactive_session_forwards_keys_and_echoes_output() {
+    let (mut host, mut pi) = pipe();
+    let (tx, mut pi_rx) = pipe();
+    let (mut keys, typed) = pipe();
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+
+    let session = thread::spawn(move || {
+        let mut out = Vec::new();
+        let outcome = session(
+            &mut host,
+            tx,
+            Some(Input::Interactive(typed)),
+            &mut out,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome.exit_code, None);
+        out
+    });
+
+    keys.write_all(b"help\n").unwrap();
+    let mut sent = [0u8; 5];
+    pi_rx.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent, b"help\n");
+
+    pi.write_all(b"no help here\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    keys.write_all(&[ESCAPE, QUIT]).unwrap();
+
+    assert_eq!(session.join().unwrap(), b"no help here\n");
+}
+
+#[test]
+fn script_is_sent_verbatim_until_the_pi_hangs_up() {
+    let (mut host, mut pi) = pipe();
+    let (tx, mut pi_rx) = pipe();
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+
+    // Escapes mean nothing in a script.
+    let script = b"echo \x01x\nexit\n".to_vec();
+    let expected = script.clone();
+    let kernel = thread::spawn(move || {
+        let mut sent = vec![0u8; expected.len()];
+        pi_rx.read_exact(&mut sent).unwrap();
+        pi.write_all(b"bye\n").unwrap();
+        sent == expected
+    });
+
+    let mut out = Vec::new();
+    let outcome = session(
+        &mut host,
+        tx,
+        Some(Input::Script(Cursor::new(script))),
+        &mut out,
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcome.exit_code, None);
+    assert!(kernel.join().unwrap());
+    assert_eq!(out, b"bye\n");
+}
+
+fn exit_frame(code: i32) -> Vec<u8> {
+    Frame::KernelExit { code: code as u32 }.encode().to_vec()
+}
+
+fn start_frame(index: u32, total: u32, name: &str) -> Vec<u8> {
+    let mut bytes = Frame::TestStart {
+        index,
+        total,
+        len: name.len() as u32,
+    }
+    .encode()
+    .to_vec();
+    bytes.extend(name.as_bytes());
+    bytes
+}
+
+fn result_frame(index: u32, status: TEST_STATUS, usec: u32) -> Vec<u8> {
+    Frame::TestResult {
+        index,
+        status: status.val(),
+        usec,
+    }
+    .encode()
+    .to_vec()
+}
+
+/// All the text in `outputs`, joined.
+fn text(outputs: &[Output]) -> Vec<u8> {
+    outputs
+        .iter()
+        .filter_map(|o| match o {
+            Output::Text(text) => Some(text.as_slice()),
+            Output::Frame(..) => None,
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+#[test]
+fn exit_frame_is_found_across_reads() {
+    let mut stream = "done » ok\n".as_bytes().to_vec();
+    stream.extend(exit_frame(-3));
+    stream.extend(b"after");
+
+    for split in 0..stream.len() {
+        let mut scanner = FrameScanner::default();
+        let mut out = Vec::new();
+        let code = scanner
+            .feed(&stream[..split], &mut out)
+            .or_else(|| scanner.feed(&stream[split..], &mut out));
+        assert_eq!(code, Some(-3), "split at {}", split);
+        assert_eq!(text(&out), "done » ok\n".as_bytes(), "split at {}", split);
+    }
+}
+
+#[test]
+fn partial_exit_frame_is_text() {
+    let mut scanner = FrameScanner::default();
+    let mut out = Vec::new();
+    let frame = exit_frame(0);
+
+    assert_eq!(scanner.feed(&frame[..3], &mut out), None);
+    assert_eq!(scanner.feed(b"x", &mut out), None);
+    scanner.finish(&mut out);
+
+    let mut expected = frame[..3].to_vec();
+    expected.push(b'x');
+    assert_eq!(out, [Output::Text(expected)]);
+}
+
+#[test]
+fn test_frames_are_found_between_text() {
+    let mut stream = b"boot\n".to_vec();
+    stream.extend(start_frame(0, 1, "thread::t3"));
+    stream.extend(b"hi\n");
+    stream.extend(result_frame(0, TEST_STATUS::PASS, 5));
+    stream.extend(b"done");
+
+    let expected = [
+        Output::Text(b"boot\n".to_vec()),
+        Output::Frame(
+            Frame::TestStart {
+                index: 0,
+                total: 1,
+                len: 10,
+            },
+            b"thread::t3".to_vec(),
+        ),
+        Output::Text(b"hi\n".to_vec()),
+        Output::Frame(
+            Frame::TestResult {
+                index: 0,
+                status: 0,
+                usec: 5,
+            },
+            vec![],
+        ),
+        Output::Text(b"done".to_vec()),
+    ];
+
+    let mut scanner = FrameScanner::default();
+    let mut out = Vec::new();
+    for byte in stream {
+        assert_eq!(scanner.feed(&[byte], &mut out), None);
+    }
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn overlong_test_name_is_text() {
+    let mut stream = Frame::TestStart {
+        index: 0,
+        total: 1,
+        len: MAX_PAYLOAD_LEN as u32 + 1,
+    }
+    .encode()
+    .to_vec();
+    stream.extend(b"name");
+
+    let mut scanner = FrameScanner::default();
+    let mut out = Vec::new();
+    assert_eq!(scanner.feed(&stream, &mut out), None);
+    scanner.finish(&mut out);
+    assert_eq!(out, [Output::Text(stream)]);
+}
+
+#[test]
+fn session_ends_with_the_kernel_exit_code() {
+    let (mut host, mut pi) = pipe();
+    let (tx, _pi_rx) = pipe();
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+
+    pi.write_all(b"test passed\n").unwrap();
+    pi.write_all(&exit_frame(7)).unwrap();
+
+    let mut out = Vec::new();
+    let outcome = session(
+        &mut host,
+        tx,
+        None::<Input<Cursor<Vec<u8>>>>,
+        &mut out,
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcome.exit_code, Some(7));
+    assert!(outcome.tests.is_empty());
+    assert_eq!(out, b"test passed\n");
+}
+
+#[test]
+fn session_collects_test_results() {
+    let (mut host, mut pi) = pipe();
+    let (tx, _pi_rx) = pipe();
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+
+    pi.write_all(b"running 3 tests\n").unwrap();
+    pi.write_all(&start_frame(0, 3, "thread::t3_test_exit::t3_test_exit"))
+        .unwrap();
+    pi.write_all(b"SUCCESS\n").unwrap();
+    pi.write_all(&result_frame(0, TEST_STATUS::PASS, 2500))
+        .unwrap();
+    pi.write_all(&start_frame(1, 3, "thread::t5::t5")).unwrap();
+    pi.write_all(b"Panic occurred\n").unwrap();
+    pi.write_all(&result_frame(1, TEST_STATUS::FAIL, 10))
+        .unwrap();
+    pi.write_all(&start_frame(2, 3, "thread::t7::t7")).unwrap();
+    pi.write_all(&result_frame(2, TEST_STATUS::TIMEOUT, 1_000_000))
+        .unwrap();
+    pi.write_all(&exit_frame(1)).unwrap();
+
+    let mut out = Vec::new();
+    let outcome = session(
+        &mut host,
+        tx,
+        None::<Input<Cursor<Vec<u8>>>>,
+        &mut out,
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcome.exit_code, Some(1));
+
+    let tests = outcome.tests;
+    let statuses = tests.cases.iter().map(|c| c.status).collect::<Vec<_>>();
+    assert_eq!(statuses, [Status::Passed, Status::Failed, Status::TimedOut]);
+    assert_eq!(tests.cases[0].output, b"SUCCESS\n");
+    assert_eq!(tests.cases[1].output, b"Panic occurred\n");
+    assert!(!tests.passed());
+
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("test thread::t3_test_exit::t3_test_exit ... ok (2 ms)\n"));
+    assert!(out.contains("test thread::t7::t7 ... TIMED OUT (1000 ms)\n"));
+}
+
+#[test]
+fn session_collects_profile_samples() {
+    let (mut host, mut pi) = pipe();
+    let (tx, _pi_rx) = pipe();
+    host.set_timeout(Duration::from_millis(10)).unwrap();
+
+    let samples = [0x8000u32, 7, 0x8004, 1];
+    pi.write_all(b"00009000: 2\n").unwrap();
+    pi.write_all(
+        &Frame::ProfileSamples {
+            period_usec: 50,
+            count: 2,
+        }
+        .encode(),
+    )
+    .unwrap();
+    for word in samples {
+        pi.write_all(&word.to_le_bytes()).unwrap();
+    }
+    pi.write_all(&exit_frame(0)).unwrap();
+
+    let mut out = Vec::new();
+    let outcome = session(
+        &mut host,
+        tx,
+        None::<Input<Cursor<Vec<u8>>>>,
+        &mut out,
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcome.profile.total(), 10);
+    assert_eq!(out, b"00009000: 2\n");
+}
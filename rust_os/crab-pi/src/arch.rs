@@ -4,3 +4,9 @@
 pub fn dsb() {
     unsafe { ::core::arch::asm!("mcr p15, 0, {t}, c7, c10, 4", t = in(reg) 0) }
 }
+
+/// Waits for an interrupt, even a masked one: with IRQs off, it is taken once they are back on.
+#[inline(always)]
+pub fn wfi() {
+    unsafe { ::core::arch::asm!("mcr p15, 0, {t}, c7, c0, 4", t = in(reg) 0) }
+}
@@ -1,2 +1,5 @@
 pub const STACK_ADDR: usize = 0x8000_0000;
 pub const INT_STACK_ADDR: usize = 0x9000_0000;
+pub const UNDEF_STACK_ADDR: usize = 0x8f00_0000;
+pub const FIQ_STACK_ADDR: usize = 0x8e00_0000;
+pub const ABORT_STACK_ADDR: usize = 0x8d00_0000;
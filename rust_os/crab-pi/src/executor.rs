@@ -0,0 +1,375 @@
+//! Async tasks, woken by interrupts.
+//!
+//! An [`Executor`] polls a task when it is woken, and waits for an interrupt while none is.
+//! The futures here wait on the hardware: [`gpio_edge`], [`sleep`] and [`sleep_until`], and
+//! [`uart_byte`]. Their handlers are dispatched by `interrupt_vector` like any other, and only
+//! wake the task waiting; the task does the work.
+//!
+//! Waiting stops the CPU with `wfi`, so threads do not run meanwhile.
+
+use crate::arch::wfi;
+use crate::gpio::{
+    GPIOEvent, gpio_event_clear, gpio_int_falling_edge, gpio_int_rising_edge,
+    gpio_interrupt_enable, gpio_interrupt_init, gpio_register_interrupt_handler, gpio_set_input,
+};
+use crate::interrupt::{
+    IRQ_REG, IrqCell, enable_interrupts, interrupt_init, interrupts_installed, interrupts_restore,
+    interrupts_save, register_irq_1_handler,
+};
+use crate::memory::dev_barrier;
+use crate::thread::is_due;
+use crate::timer::{timer_alarm_clear, timer_alarm_set, timer_get_usec};
+use crate::uart;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::{Pin, pin};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+// The system timer compare that ends sleeps; `thread` has the other one.
+const TIMER_ALARM: usize = 1;
+
+// Set by every wake, so waiting can tell whether one came since the last poll.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+// Whether `start` has registered the timer and UART handlers.
+static HANDLERS_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+struct TaskWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Relaxed);
+        WOKEN.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Arc<TaskWaker>,
+}
+
+/// Runs async tasks until they are all done.
+#[derive(Default)]
+pub struct Executor {
+    tasks: Vec<Task>,
+}
+
+impl Executor {
+    pub const fn new() -> Self {
+        Executor { tasks: Vec::new() }
+    }
+
+    /// Adds a task, first polled by the next [`run`](Self::run).
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Task {
+            future: Box::pin(future),
+            waker: Arc::new(TaskWaker {
+                woken: AtomicBool::new(true),
+            }),
+        });
+    }
+
+    /// Polls the woken tasks until every task is done, waiting for interrupts in between.
+    pub fn run(&mut self) {
+        let cpsr = start();
+        while !self.tasks.is_empty() {
+            WOKEN.store(false, Ordering::Relaxed);
+            self.tasks.retain_mut(|task| {
+                if !task.waker.woken.swap(false, Ordering::Relaxed) {
+                    return true;
+                }
+                let waker = Waker::from(task.waker.clone());
+                task.future
+                    .as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_pending()
+            });
+            if !self.tasks.is_empty() {
+                idle();
+            }
+        }
+        interrupts_restore(cpsr);
+    }
+}
+
+/// Runs `future` to completion, waiting for interrupts while it cannot go on.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let cpsr = start();
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(TaskWaker {
+        woken: AtomicBool::new(false),
+    }));
+    let output = loop {
+        WOKEN.store(false, Ordering::Relaxed);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            break output;
+        }
+        idle();
+    };
+    interrupts_restore(cpsr);
+    output
+}
+
+// Installs the interrupt table and the timer and UART handlers, once, and turns interrupts on.
+// The GPIO bank is left to the first `gpio_edge` wait. Returns the `cpsr` to restore when done.
+fn start() -> u32 {
+    let cpsr = interrupts_save();
+    unsafe {
+        if !interrupts_installed() {
+            interrupt_init();
+        }
+        if !HANDLERS_REGISTERED.swap(true, Ordering::Relaxed) {
+            register_irq_1_handler(TIMER_ALARM, timer_tick);
+            register_irq_1_handler(uart::AUX_IRQ, uart_ready);
+        }
+    }
+    enable_interrupts();
+    cpsr
+}
+
+// Waits for an interrupt, unless something was woken since `WOKEN` was cleared.
+fn idle() {
+    let cpsr = interrupts_save();
+    if !WOKEN.load(Ordering::Relaxed) {
+        // The interrupt is taken once they are back on.
+        wfi();
+    }
+    interrupts_restore(cpsr);
+}
+
+struct Timer {
+    id: u32,
+    deadline: u32,
+    waker: Waker,
+}
+
+struct Timers {
+    next_id: u32,
+    waiting: Vec<Timer>,
+}
+
+static TIMERS: IrqCell<Timers> = IrqCell::new(Timers {
+    next_id: 0,
+    waiting: Vec::new(),
+});
+
+/// Waits for at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(timer_get_usec().wrapping_add(duration.as_micros() as u32))
+}
+
+/// Waits until [`timer_get_usec`] reaches `deadline`, less than 2^31 usec away.
+pub fn sleep_until(deadline: u32) -> Sleep {
+    Sleep { deadline, id: None }
+}
+
+/// The future of [`sleep`] and [`sleep_until`].
+pub struct Sleep {
+    deadline: u32,
+    // Its place in `TIMERS`, once polled.
+    id: Option<u32>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let deadline = self.deadline;
+        if is_due(deadline, timer_get_usec()) {
+            return Poll::Ready(());
+        }
+        TIMERS.with(|timers| {
+            let id = *self.id.get_or_insert_with(|| {
+                timers.next_id = timers.next_id.wrapping_add(1);
+                timers.next_id
+            });
+            match timers.waiting.iter_mut().find(|timer| timer.id == id) {
+                Some(timer) => timer.waker.clone_from(cx.waker()),
+                None => timers.waiting.push(Timer {
+                    id,
+                    deadline,
+                    waker: cx.waker().clone(),
+                }),
+            }
+            unsafe { wake_timers(timers) };
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            TIMERS.with(|timers| timers.waiting.retain(|timer| timer.id != id));
+        }
+    }
+}
+
+// Wakes the sleeps that are due and sets the alarm for the next. Interrupts must be off.
+unsafe fn wake_timers(timers: &mut Timers) {
+    loop {
+        let now = timer_get_usec();
+        timers.waiting.retain(|timer| {
+            let due = is_due(timer.deadline, now);
+            if due {
+                timer.waker.wake_by_ref();
+            }
+            !due
+        });
+        let Some(next) = timers
+            .waiting
+            .iter()
+            .map(|timer| timer.deadline)
+            .min_by_key(|deadline| deadline.wrapping_sub(now) as i32)
+        else {
+            return;
+        };
+        unsafe { timer_alarm_set(TIMER_ALARM, next) };
+        // It only gets its interrupt if it was set in time.
+        if !is_due(next, timer_get_usec()) {
+            return;
+        }
+    }
+}
+
+fn timer_tick(_pc: u32) {
+    unsafe {
+        timer_alarm_clear(TIMER_ALARM);
+        wake_timers(TIMERS.get());
+    }
+}
+
+struct GpioWaits {
+    // Pins whose edge came since they were armed.
+    seen: u32,
+    waiting: [Option<(GPIOEvent, Waker)>; 32],
+}
+
+static GPIO_WAITS: IrqCell<GpioWaits> = IrqCell::new(GpioWaits {
+    seen: 0,
+    waiting: [const { None }; 32],
+});
+
+/// Waits for `edge` on `pin`, one of the first 32, made an input. One task at a time may wait
+/// on a pin.
+pub fn gpio_edge(pin: u32, edge: GPIOEvent) -> GpioEdge {
+    assert!(pin < 32, "Invalid GPIO pin number");
+    GpioEdge {
+        pin,
+        edge,
+        armed: false,
+    }
+}
+
+/// The future of [`gpio_edge`].
+pub struct GpioEdge {
+    pin: u32,
+    edge: GPIOEvent,
+    armed: bool,
+}
+
+impl Future for GpioEdge {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let (pin, edge) = (self.pin, self.edge);
+        GPIO_WAITS.with(|waits| {
+            if !self.armed {
+                // Only edges from now on count.
+                self.armed = true;
+                waits.seen &= !(1 << pin);
+                gpio_set_input(pin);
+                gpio_event_clear(pin);
+                gpio_register_interrupt_handler(pin, gpio_wake);
+                match edge {
+                    GPIOEvent::RisingEdge => gpio_int_rising_edge(pin),
+                    GPIOEvent::FallingEdge => gpio_int_falling_edge(pin),
+                }
+                gpio_interrupt_init();
+                gpio_interrupt_enable();
+            } else if waits.seen & (1 << pin) != 0 {
+                waits.seen &= !(1 << pin);
+                return Poll::Ready(());
+            }
+            waits.waiting[pin as usize] = Some((edge, cx.waker().clone()));
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for GpioEdge {
+    fn drop(&mut self) {
+        if self.armed {
+            GPIO_WAITS.with(|waits| waits.waiting[self.pin as usize] = None);
+        }
+    }
+}
+
+// Called by `gpio_irq_handler`, with interrupts off.
+fn gpio_wake(pin: u32, event: GPIOEvent) {
+    let waits = unsafe { GPIO_WAITS.get() };
+    let waiting = &mut waits.waiting[pin as usize];
+    if waiting.as_ref().is_some_and(|&(edge, _)| edge == event) {
+        let (_, waker) = waiting.take().unwrap();
+        waits.seen |= 1 << pin;
+        waker.wake();
+    }
+}
+
+static UART_WAITING: IrqCell<Option<Waker>> = IrqCell::new(None);
+
+/// Waits for a byte from the UART, and reads it.
+pub fn uart_byte() -> UartByte {
+    UartByte(())
+}
+
+/// The future of [`uart_byte`].
+pub struct UartByte(());
+
+impl Future for UartByte {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        let cpsr = interrupts_save();
+        if uart::can_read() {
+            interrupts_restore(cpsr);
+            let mut byte = [0];
+            uart::read_bytes(&mut byte);
+            return Poll::Ready(byte[0]);
+        }
+        unsafe {
+            *UART_WAITING.get() = Some(cx.waker().clone());
+            uart::rx_interrupt_enable();
+            IRQ_REG::ENABLE_1
+                .as_mut_ptr::<u32>()
+                .write_volatile(1 << uart::AUX_IRQ);
+            dev_barrier();
+        }
+        interrupts_restore(cpsr);
+        Poll::Pending
+    }
+}
+
+impl Drop for UartByte {
+    fn drop(&mut self) {
+        UART_WAITING.with(|waiting| *waiting = None);
+    }
+}
+
+// The interrupt stays up until the byte is read, so it is off until the next poll.
+fn uart_ready(_pc: u32) {
+    uart::rx_interrupt_disable();
+    if let Some(waker) = unsafe { UART_WAITING.get() }.take() {
+        waker.wake();
+    }
+}
@@ -0,0 +1,29 @@
+//! Ending a kernel: tell `pi-install` the exit code, then reboot into the bootloader.
+
+use boot_proto::Frame;
+use core::sync::atomic::{AtomicI32, Ordering};
+
+/// Exit code reported for a panic, the same one Rust programs use.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+// What `__kernel_start` reports once `__user_main` returns.
+static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
+/// Sets the code reported when `__user_main` returns.
+pub fn set_exit_code(code: i32) {
+    EXIT_CODE.store(code, Ordering::Relaxed);
+}
+
+/// The code `__kernel_start` will report.
+pub fn exit_code() -> i32 {
+    EXIT_CODE.load(Ordering::Relaxed)
+}
+
+/// Sends `[KERNEL_EXIT, code]` after any pending output and reboots.
+pub fn exit(code: i32) -> ! {
+    crate::uart::flush();
+    crate::uart::write_bytes(&Frame::KernelExit { code: code as u32 }.encode());
+    crate::uart::flush();
+
+    crate::watchdog::restart()
+}
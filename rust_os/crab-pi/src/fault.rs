@@ -0,0 +1,162 @@
+//! What the CPU was doing when it faulted, for the abort and undefined instruction handlers.
+//!
+//! The handlers in `interrupts-asm.S` run on stacks of their own, so a fault from a thread
+//! that overflowed its stack still gets reported. They save a [`FaultFrame`]; aborts also
+//! leave their cause in the CP15 fault status registers, which [`FaultKind`] decodes.
+
+use crate::interrupt::SYS_MODE;
+use core::fmt;
+use core::ptr::with_exposed_provenance;
+use macros::cp_asm_get;
+
+cp_asm_get!(dfsr, p15, 0, c5, c0, 0);
+cp_asm_get!(ifsr, p15, 0, c5, c0, 1);
+cp_asm_get!(far, p15, 0, c6, c0, 0);
+
+// DFSR: the abort was on a write.
+const DFSR_WNR: u32 = 1 << 11;
+
+/// The registers of the code that faulted, lowest address first, as the handlers push them.
+#[repr(C)]
+pub struct FaultFrame {
+    pub r: [u32; 13],
+    pub sp: u32,
+    pub lr: u32,
+    /// The instruction that faulted.
+    pub pc: u32,
+    pub cpsr: u32,
+    // Keeps the frame a multiple of 8 bytes.
+    _pad: u32,
+}
+
+impl FaultFrame {
+    /// The mode the code that faulted ran in.
+    pub fn mode(&self) -> Option<SYS_MODE> {
+        SYS_MODE::from_u32(self.cpsr & 0b11111)
+    }
+}
+
+impl fmt::Display for FaultFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let named = [("sp", self.sp), ("lr", self.lr), ("pc", self.pc)];
+        for (i, (name, value)) in (0..13)
+            .map(|i| (REGISTER_NAMES[i], self.r[i]))
+            .chain(named)
+            .enumerate()
+        {
+            write!(f, "{:>3}={:#010x}", name, value)?;
+            f.write_str(if i % 4 == 3 { "\n" } else { "  " })?;
+        }
+        write!(f, "cpsr={:#010x} ({:?} mode", self.cpsr, self.mode())?;
+        if self.cpsr & (1 << 7) != 0 {
+            f.write_str(", IRQs off")?;
+        }
+        f.write_str(")")
+    }
+}
+
+const REGISTER_NAMES: [&str; 13] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12",
+];
+
+/// The cause of an abort, from the status bits of the DFSR or IFSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    Alignment,
+    /// No mapping for the address, at the first level (section) or the second (page).
+    Translation {
+        page: bool,
+    },
+    AccessFlag {
+        page: bool,
+    },
+    Domain {
+        page: bool,
+    },
+    Permission {
+        page: bool,
+    },
+    /// The memory system refused the access. Imprecise ones are reported after the
+    /// instruction that caused them, so `pc` is not it.
+    ExternalAbort {
+        imprecise: bool,
+    },
+    /// An external abort while walking the translation tables.
+    TableWalk {
+        page: bool,
+    },
+    InstructionCacheMaintenance,
+    Debug,
+    Unknown(u32),
+}
+
+impl FaultKind {
+    /// Decodes the fault status bits, `[10]` and `[3:0]`, of `status`.
+    pub fn from_status(status: u32) -> Self {
+        match (status >> 6) & 0b10000 | status & 0b1111 {
+            0b00001 | 0b00011 => FaultKind::Alignment,
+            0b00100 => FaultKind::InstructionCacheMaintenance,
+            0b00101 => FaultKind::Translation { page: false },
+            0b00111 => FaultKind::Translation { page: true },
+            0b00110 => FaultKind::AccessFlag { page: true },
+            0b01001 => FaultKind::Domain { page: false },
+            0b01011 => FaultKind::Domain { page: true },
+            0b01101 => FaultKind::Permission { page: false },
+            0b01111 => FaultKind::Permission { page: true },
+            0b01000 => FaultKind::ExternalAbort { imprecise: false },
+            0b10110 => FaultKind::ExternalAbort { imprecise: true },
+            0b01100 => FaultKind::TableWalk { page: false },
+            0b01110 => FaultKind::TableWalk { page: true },
+            0b00010 => FaultKind::Debug,
+            fs => FaultKind::Unknown(fs),
+        }
+    }
+}
+
+/// Prints a data abort from `frame` and panics.
+pub(crate) fn data_abort(frame: &FaultFrame) -> ! {
+    let status = dfsr_get();
+    let access = if status & DFSR_WNR != 0 {
+        "write"
+    } else {
+        "read"
+    };
+    crate::println!(
+        "data abort: {:?} on a {} of {:#010x} (DFSR={:#x})",
+        FaultKind::from_status(status),
+        access,
+        far_get(),
+        status
+    );
+    dump(frame, true);
+    panic!("data abort at pc={:#x}", frame.pc);
+}
+
+/// Prints a prefetch abort from `frame` and panics.
+pub(crate) fn prefetch_abort(frame: &FaultFrame) -> ! {
+    let status = ifsr_get();
+    crate::println!(
+        "prefetch abort: {:?} fetching {:#010x} (IFSR={:#x})",
+        FaultKind::from_status(status),
+        frame.pc,
+        status
+    );
+    // Fetching the instruction is what failed.
+    dump(frame, false);
+    panic!("prefetch abort at pc={:#x}", frame.pc);
+}
+
+/// Prints an undefined instruction from `frame` and panics.
+pub(crate) fn undefined_instruction(frame: &FaultFrame) -> ! {
+    crate::println!("undefined instruction at {:#010x}", frame.pc);
+    dump(frame, true);
+    panic!("undefined instruction at pc={:#x}", frame.pc);
+}
+
+fn dump(frame: &FaultFrame, fetchable: bool) {
+    if fetchable {
+        let instruction = unsafe { with_exposed_provenance::<u32>(frame.pc as usize).read() };
+        crate::println!("instruction={:#010x}", instruction);
+    }
+    crate::println!("{}", frame);
+}
@@ -1,4 +1,6 @@
-use crate::interrupt::{IRQ_REG, IrqHandler, register_irq_2_handler};
+use crate::interrupt::{
+    FiqSource, IRQ_REG, IrqHandler, register_fiq_handler, register_irq_2_handler,
+};
 use crate::memory::dev_barrier;
 use crate::println;
 use core::cell::SyncUnsafeCell;
@@ -140,7 +142,7 @@ pub fn gpio_read(mut pin: u32) -> bool {
 const GPIO0_IRQ_MASK: u32 = 0x1 << 17;
 
 type GPIOHandlerFn = fn(u32, GPIOEvent);
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum GPIOEvent {
     RisingEdge,
     FallingEdge,
@@ -223,6 +225,12 @@ pub fn gpio_interrupt_init() {
     unsafe { register_irq_2_handler(17, gpio_irq_handler) }
 }
 
+/// [`gpio_interrupt_init`] as the FIQ instead, for handlers that cannot wait behind other
+/// interrupts; see [`register_fiq_handler`]. Do not call [`gpio_interrupt_enable`] with it.
+pub fn gpio_fiq_init() {
+    unsafe { register_fiq_handler(FiqSource::Irq2(17), gpio_irq_handler) }
+}
+
 pub fn gpio_interrupt_enable() {
     dev_barrier();
     unsafe {
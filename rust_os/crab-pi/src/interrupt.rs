@@ -1,10 +1,12 @@
-use crate::constant::INT_STACK_ADDR;
+use crate::constant::{ABORT_STACK_ADDR, FIQ_STACK_ADDR, INT_STACK_ADDR, UNDEF_STACK_ADDR};
 use crate::cycle_count::cycle_cnt_read;
+use crate::fault::{self, FaultFrame};
 use crate::memory::{dev_barrier, gcc_mb};
 use crate::println;
-use crate::vector_base::vector_base_reset;
+use crate::vector_base::{vector_base_get, vector_base_reset};
 use crate::watchdog::clean_reboot;
 use core::arch::{asm, global_asm};
+use core::cell::UnsafeCell;
 use core::ptr::{with_exposed_provenance, with_exposed_provenance_mut};
 use log::trace;
 use macros::{enum_ptr, enum_u32};
@@ -48,10 +50,46 @@ pub type IrqHandler = fn(u32);
 static mut IRQ_BASIC_HANDLERS: [Option<IrqHandler>; 32] = [None; 32];
 static mut IRQ_1_HANDLERS: [Option<IrqHandler>; 32] = [None; 32];
 static mut IRQ_2_HANDLERS: [Option<IrqHandler>; 32] = [None; 32];
+static mut FIQ_HANDLER: Option<IrqHandler> = None;
+
+// `FIQ_CONTROL`: FIQs on, for the source in the low bits.
+const FIQ_ENABLE: u32 = 1 << 7;
+
+/// An interrupt that can be made the FIQ, indexed as in the `register_irq_*_handler`s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FiqSource {
+    /// One of the first 8 basic interrupts: the ARM timer is 0.
+    Basic(usize),
+    Irq1(usize),
+    Irq2(usize),
+}
+
+impl FiqSource {
+    // Its number in `FIQ_CONTROL`, and its bit in the `ENABLE`/`DISABLE` register for it.
+    fn control(self) -> (u32, IRQ_REG, u32) {
+        match self {
+            FiqSource::Basic(irq) => {
+                assert!(irq < 8, "basic IRQ {} cannot be the FIQ", irq);
+                (64 + irq as u32, IRQ_REG::DISABLE_BASIC, 1 << irq)
+            }
+            FiqSource::Irq1(index) => {
+                assert!(index < 32, "no IRQ 1 index {}", index);
+                (index as u32, IRQ_REG::DISABLE_1, 1 << index)
+            }
+            FiqSource::Irq2(index) => {
+                assert!(index < 32, "no IRQ 2 index {}", index);
+                (32 + index as u32, IRQ_REG::DISABLE_2, 1 << index)
+            }
+        }
+    }
+}
 
 global_asm!(
     include_str!("../asm/interrupts-asm.S"),
     INT_STACK_ADDR = const INT_STACK_ADDR,
+    FIQ_STACK_ADDR = const FIQ_STACK_ADDR,
+    UNDEF_STACK_ADDR = const UNDEF_STACK_ADDR,
+    ABORT_STACK_ADDR = const ABORT_STACK_ADDR,
     fast_interrupt_vector = sym fast_interrupt_vector,
     interrupt_vector = sym interrupt_vector,
     reset_vector = sym reset_vector,
@@ -70,7 +108,21 @@ unsafe extern "C" {
 
 #[unsafe(no_mangle)]
 extern "C" fn fast_interrupt_vector(pc: u32) {
-    panic!("not implemented yet");
+    match unsafe { FIQ_HANDLER } {
+        Some(handler) => handler(pc),
+        None => panic!("FIQ without a handler at pc={:#x}", pc),
+    }
+}
+
+/// Lets FIQs through; `_start` leaves them off. Not touched by [`interrupts_save`].
+#[inline]
+pub fn enable_fiq() {
+    unsafe { asm!("cpsie f") };
+}
+
+#[inline]
+pub fn disable_fiq() {
+    unsafe { asm!("cpsid f") };
 }
 
 #[inline]
@@ -91,8 +143,53 @@ pub fn spsr_get() -> u32 {
     spsr & 0b11111
 }
 
+/// Disables interrupts, returning the `cpsr` from before for [`interrupts_restore`].
+#[inline]
+pub fn interrupts_save() -> u32 {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs {}, cpsr", "cpsid i", out(reg) cpsr);
+    }
+    cpsr
+}
+
+/// Puts interrupts back the way they were before [`interrupts_save`].
+#[inline]
+pub fn interrupts_restore(cpsr: u32) {
+    unsafe {
+        asm!("msr cpsr_c, {}", in(reg) cpsr);
+    }
+}
+
+/// State shared with interrupt handlers, only touched with interrupts off.
+pub(crate) struct IrqCell<T>(UnsafeCell<T>);
+
+unsafe impl<T: Send> Sync for IrqCell<T> {}
+
+impl<T> IrqCell<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        IrqCell(UnsafeCell::new(value))
+    }
+
+    // Interrupts must be off, and the reference dropped before anything else can take one.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn get(&self) -> &mut T {
+        unsafe { &mut *self.0.get() }
+    }
+
+    // Runs `f` with interrupts off.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let cpsr = interrupts_save();
+        let result = f(unsafe { self.get() });
+        interrupts_restore(cpsr);
+        result
+    }
+}
+
+// Returns true to preempt the interrupted thread instead of returning to it.
 #[unsafe(no_mangle)]
-unsafe extern "C" fn interrupt_vector(pc: u32) {
+unsafe extern "C" fn interrupt_vector(pc: u32) -> bool {
+    unsafe { crate::vfp::irq_enter() };
     dev_barrier();
 
     // Check BASIC IRQ
@@ -125,6 +222,9 @@ unsafe extern "C" fn interrupt_vector(pc: u32) {
         pending_irq2 &= !(1 << i);
     }
     dev_barrier();
+    unsafe { crate::vfp::irq_exit() };
+
+    crate::thread::rpi_preempt_pending()
 }
 
 #[unsafe(no_mangle)]
@@ -133,8 +233,11 @@ extern "C" fn reset_vector(pc: u32) {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn undefined_instruction_vector(pc: u32) {
-    panic!("undefined instruction");
+extern "C" fn undefined_instruction_vector(frame: &FaultFrame) {
+    if unsafe { crate::vfp::trap(frame.pc) } {
+        return;
+    }
+    fault::undefined_instruction(frame);
 }
 
 #[unsafe(no_mangle)]
@@ -172,13 +275,13 @@ extern "C" fn syscall_vector(pc: usize, r0: usize) -> i32 {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn prefetch_abort_vector(pc: u32) {
-    panic!("prefetch abort");
+extern "C" fn prefetch_abort_vector(frame: &FaultFrame) -> ! {
+    fault::prefetch_abort(frame)
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn data_abort_vector(pc: u32) {
-    panic!("data abort");
+extern "C" fn data_abort_vector(frame: &FaultFrame) -> ! {
+    fault::data_abort(frame)
 }
 
 pub unsafe fn interrupt_init() {
@@ -192,6 +295,7 @@ pub unsafe fn interrupt_init() {
     IRQ_REG::DISABLE_2
         .as_mut_ptr::<u32>()
         .write_volatile(0xffffffff);
+    unsafe { IRQ_REG::FIQ_CONTROL.as_mut_ptr::<u32>().write_volatile(0) };
 
     dev_barrier();
 
@@ -205,6 +309,11 @@ pub unsafe fn interrupt_init() {
     vector_base_reset(interrupt_table);
 }
 
+/// True once [`interrupt_init`] has installed the interrupt table.
+pub fn interrupts_installed() -> bool {
+    vector_base_get() == &_interrupt_table as *const u32
+}
+
 pub unsafe fn register_irq_basic_handler(irq: usize, handler: IrqHandler) {
     println!("Registered handler for IRQ {}", irq);
     IRQ_BASIC_HANDLERS[irq] = Some(handler);
@@ -219,3 +328,41 @@ pub unsafe fn register_irq_2_handler(index: usize, handler: IrqHandler) {
     println!("Registered handler for IRQ 2 index: {}", index);
     IRQ_2_HANDLERS[index] = Some(handler);
 }
+
+/// Makes `source` the FIQ, handled by `handler`, in place of the one before: only one source
+/// can be. Its IRQ is disabled, so enable the source's interrupt in its peripheral first, and
+/// not in `IRQ_REG`. FIQs must also be let through with [`enable_fiq`].
+///
+/// The FIQ is taken even with IRQs off, on its own stack and with r8-r12 of its own, so it
+/// gets in sooner than any IRQ handler could. In exchange `handler` may interrupt anything,
+/// IRQ handlers and code with IRQs off included: it can only share atomics with the rest, and
+/// must not use floats.
+pub unsafe fn register_fiq_handler(source: FiqSource, handler: IrqHandler) {
+    let (number, disable, bit) = source.control();
+    dev_barrier();
+    // No FIQ comes while the handler changes.
+    unsafe {
+        IRQ_REG::FIQ_CONTROL.as_mut_ptr::<u32>().write_volatile(0);
+        disable.as_mut_ptr::<u32>().write_volatile(bit);
+    }
+    dev_barrier();
+
+    unsafe { FIQ_HANDLER = Some(handler) };
+    println!("Registered FIQ handler for {:?}", source);
+
+    dev_barrier();
+    unsafe {
+        IRQ_REG::FIQ_CONTROL
+            .as_mut_ptr::<u32>()
+            .write_volatile(FIQ_ENABLE | number);
+    }
+    dev_barrier();
+}
+
+/// Routes no source to the FIQ.
+pub unsafe fn unregister_fiq_handler() {
+    dev_barrier();
+    unsafe { IRQ_REG::FIQ_CONTROL.as_mut_ptr::<u32>().write_volatile(0) };
+    dev_barrier();
+    unsafe { FIQ_HANDLER = None };
+}
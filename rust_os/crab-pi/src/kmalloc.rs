@@ -24,6 +24,7 @@ void *kmalloc_heap_start(void);
 // pointer to end of heap
 void *kmalloc_heap_end(void);
  */
+use crate::interrupt::{interrupts_restore, interrupts_save};
 use crate::println;
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::ptr::{NonNull, with_exposed_provenance_mut};
@@ -45,6 +46,14 @@ unsafe extern "C" {
 
 }
 
+// kmalloc is not reentrant, and a thread may be preempted in the middle of it.
+fn alloc_aligned(nbytes: usize, alignment: usize) -> *mut u8 {
+    let cpsr = interrupts_save();
+    let ptr = unsafe { kmalloc_aligned(nbytes, alignment) };
+    interrupts_restore(cpsr);
+    ptr
+}
+
 pub fn kmalloc_alloc<T>() -> *mut T {
     unsafe { kmalloc(size_of::<T>()) as *mut T }
 }
@@ -62,7 +71,7 @@ impl KmallocAllocator {
 
 impl Default for KmallocAllocator {
     fn default() -> Self {
-        let MB: usize = 1024 * 1024;
+        const MB: usize = 1024 * 1024;
 
         unsafe { kmalloc_init_set_start(with_exposed_provenance_mut(MB), 64 * MB) };
         Self
@@ -72,7 +81,7 @@ impl Default for KmallocAllocator {
 unsafe impl Allocator for KmallocAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let alignment = layout.align();
-        let start = unsafe { kmalloc_aligned(layout.size(), alignment) };
+        let start = alloc_aligned(layout.size(), alignment);
         let non_null_arr = unsafe { NonNull::new_unchecked(start) };
         Ok(NonNull::slice_from_raw_parts(non_null_arr, layout.size()))
     }
@@ -92,7 +101,7 @@ unsafe impl Allocator for KmallocAllocator {
         new_layout: Layout,
     ) -> Result<NonNull<[u8]>, AllocError> {
         let alignment = new_layout.align();
-        let start = unsafe { kmalloc_aligned(new_layout.size(), alignment) };
+        let start = alloc_aligned(new_layout.size(), alignment);
         for i in 0..old_layout.size() {
             start.add(i).write(ptr.as_ptr().add(i).read());
         }
@@ -120,7 +129,7 @@ unsafe impl Allocator for KmallocAllocator {
         new_layout: Layout,
     ) -> Result<NonNull<[u8]>, AllocError> {
         let alignment = new_layout.align();
-        let start = unsafe { kmalloc_aligned(new_layout.size(), alignment) };
+        let start = alloc_aligned(new_layout.size(), alignment);
         for i in 0..new_layout.size() {
             start.add(i).write(ptr.as_ptr().add(i).read());
         }
@@ -154,7 +163,7 @@ unsafe impl GlobalAlloc for KmallocAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         println!("Allocating {} size", layout.size());
         ensure_init_default();
-        let ptr = kmalloc_aligned(layout.size(), layout.align());
+        let ptr = alloc_aligned(layout.size(), layout.align());
         println!("Allocated at {:p}", ptr);
         ptr
     }
@@ -169,7 +178,7 @@ unsafe impl GlobalAlloc for KmallocAllocator {
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
         println!("Allocating {} size", layout.size());
         ensure_init_default();
-        let ptr = kmalloc_aligned(layout.size(), layout.align());
+        let ptr = alloc_aligned(layout.size(), layout.align());
         println!("Allocated at {:p}", ptr);
         ptr
     }
@@ -177,7 +186,7 @@ unsafe impl GlobalAlloc for KmallocAllocator {
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         println!("Reallocating from {} to {} bytes", layout.size(), new_size);
         ensure_init_default();
-        let new_ptr = kmalloc_aligned(new_size, layout.align());
+        let new_ptr = alloc_aligned(new_size, layout.align());
         // Copy old data to new allocation
         let copy_size = layout.size().min(new_size);
         core::ptr::copy_nonoverlapping(ptr, new_ptr, copy_size);
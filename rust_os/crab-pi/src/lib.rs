@@ -13,6 +13,9 @@ mod arch;
 pub mod cache;
 mod constant;
 pub mod cycle_count;
+pub mod executor;
+pub mod exit;
+pub mod fault;
 pub mod gpio;
 pub mod interrupt;
 pub mod kmalloc;
@@ -22,8 +25,12 @@ pub mod mailbox;
 pub mod memory;
 mod panic_infra;
 pub mod print;
+pub mod profiler;
+pub mod testing;
 pub mod thread;
 pub mod timer;
+mod tls;
 pub mod uart;
 pub mod vector_base;
+mod vfp;
 pub mod watchdog;
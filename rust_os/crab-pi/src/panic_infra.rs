@@ -16,5 +16,8 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
     crate::arch::dsb();
 
-    crate::watchdog::restart();
+    // Inside a `cargo test` kernel this fails the test instead.
+    crate::testing::on_panic();
+
+    crate::exit::exit(crate::exit::PANIC_EXIT_CODE);
 }
@@ -0,0 +1,176 @@
+//! Finding where a kernel spends its time by sampling the PC from the ARM timer interrupt.
+//!
+//! ```ignore
+//! profiler::start(Duration::from_micros(100));
+//! work();
+//! profiler::stop();
+//! profiler::dump();
+//! ```
+//!
+//! Each sample counts one hit for the word the interrupted PC points at. [`dump`] sends the
+//! counts to `pi-install` as `PROFILE_SAMPLES` frames; `pi-install --gprof` ranks them by
+//! function and by source line.
+//!
+//! By default the whole of `.text` is profiled; [`set_range`] narrows it down, and samples
+//! outside the range are only counted. The profiler owns the ARM timer interrupt while it
+//! runs, so it cannot be used under `crab_pi::testing` or next to a timer handler of the
+//! kernel's own.
+
+#![allow(static_mut_refs)]
+
+use crate::interrupt::{
+    enable_interrupts, interrupt_init, interrupts_installed, register_irq_basic_handler,
+};
+use crate::memory::dev_barrier;
+use crate::println;
+use crate::timer::{ARM_TIMER_HZ, clear_irq, timer_init, timer_stop};
+use alloc::boxed::Box;
+use alloc::vec;
+use boot_proto::{Frame, PROFILE_SAMPLE_LEN};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+unsafe extern "C" {
+    safe static __code_start__: [u32; 0];
+    safe static __code_end__: [u32; 0];
+}
+
+// Samples per `PROFILE_SAMPLES` frame: pi-install waits for at most 1024 payload bytes.
+const BATCH: usize = 1024 / PROFILE_SAMPLE_LEN;
+
+// One counter per word of the profiled range, allocated by the first `start`.
+static mut COUNTS: Option<Box<[u32]>> = None;
+// The profiled range; `RANGE_END == 0` means `.text`, until `start` fills it in.
+static RANGE_START: AtomicU32 = AtomicU32::new(0);
+static RANGE_END: AtomicU32 = AtomicU32::new(0);
+static PERIOD_USEC: AtomicU32 = AtomicU32::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// All samples, and the ones outside the range.
+static SAMPLES: AtomicU32 = AtomicU32::new(0);
+static MISSED: AtomicU32 = AtomicU32::new(0);
+
+/// Profiles only the code in `range` instead of all of `.text`. Clears the counts; call it
+/// while the profiler is stopped.
+pub fn set_range(range: Range<u32>) {
+    assert!(!RUNNING.load(Ordering::Relaxed), "profiler is running");
+    assert!(range.start < range.end, "empty profile range");
+    RANGE_START.store(range.start & !3, Ordering::Relaxed);
+    RANGE_END.store(range.end, Ordering::Relaxed);
+    unsafe { COUNTS = None };
+    reset();
+}
+
+/// Starts sampling every `period`, or changes the period if already started.
+///
+/// Installs the interrupt table if [`interrupt_init`] has not been called, and leaves
+/// interrupts enabled.
+pub fn start(period: Duration) {
+    unsafe {
+        if COUNTS.is_none() {
+            if RANGE_END.load(Ordering::Relaxed) == 0 {
+                RANGE_START.store(&__code_start__ as *const u32 as u32, Ordering::Relaxed);
+                RANGE_END.store(&__code_end__ as *const u32 as u32, Ordering::Relaxed);
+            }
+            let words = (RANGE_END.load(Ordering::Relaxed) - RANGE_START.load(Ordering::Relaxed))
+                .div_ceil(4);
+            COUNTS = Some(vec![0; words as usize].into_boxed_slice());
+        }
+
+        if !interrupts_installed() {
+            interrupt_init();
+        }
+        let cycles = period.as_micros() as u64 * ARM_TIMER_HZ as u64 / 1_000_000;
+        PERIOD_USEC.store(period.as_micros() as u32, Ordering::Relaxed);
+        register_irq_basic_handler(0, sample);
+        timer_init(1, cycles.clamp(1, u32::MAX as u64) as u32);
+    }
+
+    RUNNING.store(true, Ordering::Relaxed);
+    enable_interrupts();
+}
+
+/// Stops sampling; the counts are kept for [`dump`].
+pub fn stop() {
+    unsafe { timer_stop() };
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Clears the counts.
+pub fn reset() {
+    unsafe {
+        if let Some(counts) = COUNTS.as_mut() {
+            counts.fill(0);
+        }
+    }
+    SAMPLES.store(0, Ordering::Relaxed);
+    MISSED.store(0, Ordering::Relaxed);
+}
+
+/// How many samples have been taken since the last [`reset`], in the range or not.
+pub fn samples() -> u32 {
+    SAMPLES.load(Ordering::Relaxed)
+}
+
+fn sample(pc: u32) {
+    unsafe {
+        dev_barrier();
+        clear_irq();
+        dev_barrier();
+    }
+
+    SAMPLES.fetch_add(1, Ordering::Relaxed);
+    let offset = pc.wrapping_sub(RANGE_START.load(Ordering::Relaxed));
+    match unsafe { COUNTS.as_mut() }.and_then(|counts| counts.get_mut(offset as usize / 4)) {
+        Some(count) if pc < RANGE_END.load(Ordering::Relaxed) => *count += 1,
+        _ => {
+            MISSED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn send(batch: &[u8]) {
+    let frame = Frame::ProfileSamples {
+        period_usec: PERIOD_USEC.load(Ordering::Relaxed),
+        count: (batch.len() / PROFILE_SAMPLE_LEN) as u32,
+    };
+    crate::uart::flush();
+    crate::uart::write_bytes(&frame.encode());
+    crate::uart::write_bytes(batch);
+    crate::uart::flush();
+}
+
+/// Sends every word with samples to `pi-install`, as `(address, hits)` pairs. Stop the
+/// profiler first for a consistent picture.
+pub fn dump() {
+    println!(
+        "profile: {} samples, {} outside {:#x}..{:#x}",
+        samples(),
+        MISSED.load(Ordering::Relaxed),
+        RANGE_START.load(Ordering::Relaxed),
+        RANGE_END.load(Ordering::Relaxed)
+    );
+
+    let Some(counts) = (unsafe { COUNTS.as_ref() }) else {
+        return;
+    };
+    let start = RANGE_START.load(Ordering::Relaxed);
+    let mut batch = [0u8; BATCH * PROFILE_SAMPLE_LEN];
+    let mut len = 0;
+    for (i, &hits) in counts.iter().enumerate() {
+        if hits == 0 {
+            continue;
+        }
+        let addr = start + i as u32 * 4;
+        batch[len..len + 4].copy_from_slice(&addr.to_le_bytes());
+        batch[len + 4..len + 8].copy_from_slice(&hits.to_le_bytes());
+        len += PROFILE_SAMPLE_LEN;
+        if len == batch.len() {
+            send(&batch);
+            len = 0;
+        }
+    }
+    if len != 0 {
+        send(&batch[..len]);
+    }
+}
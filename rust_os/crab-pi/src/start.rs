@@ -13,8 +13,9 @@ unsafe extern "Rust" {
 }
 
 extern "C" fn __kernel_start() {
+    unsafe { crate::tls::init_kernel() };
     __user_main();
-    crate::watchdog::restart()
+    crate::exit::exit(crate::exit::exit_code())
 }
 
 // This copies what staff-start.S does:
@@ -64,6 +65,16 @@ _start:
     mov r0, #0
     mcr p15, 0, r0, c7, c5, 4
 
+    // Allow access to CP10 and CP11, the VFP, then turn it on (FPEXC.EN). It belongs to the
+    // kernel until a thread uses it; see `crate::vfp`.
+    mrc p15, 0, r0, c1, c0, 2
+    orr r0, r0, {VFP_ACCESS}
+    mcr p15, 0, r0, c1, c0, 2
+    mov r0, #0
+    mcr p15, 0, r0, c7, c5, 4
+    mov r0, {FPEXC_EN}
+    vmsr fpexc, r0
+
     // Clear the BSS (not very efficient; could be faster)
     mov r0, #0
     ldr r1, ={BSS_START}
@@ -95,6 +106,8 @@ _start:
     CLEAR_MODE_MASK = const !0b11111u32,
     SUPER_MODE = const 0b10011u32,
     CLEAR_MODE_IRQ_FIQ = const (1u32 << 7) | (1u32 << 6),
+    VFP_ACCESS = const 0b1111u32 << 20,
+    FPEXC_EN = const 1u32 << 30,
     BSS_START = sym __bss_start__,
     BSS_END = sym __bss_end__,
     STACK_INIT = sym __stack_init__,
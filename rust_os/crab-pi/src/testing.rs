@@ -0,0 +1,250 @@
+//! Running `#[test_case]`s on the pi under `cargo test`.
+//!
+//! A test crate opts in with `custom_test_frameworks`, then calls the generated `test_main`
+//! from `__user_main`:
+//!
+//! ```ignore
+//! #![feature(custom_test_frameworks)]
+//! #![test_runner(crab_pi::testing::run_tests)]
+//! #![reexport_test_harness_main = "test_main"]
+//! ```
+//!
+//! Each test is framed by `TEST_START` and `TEST_RESULT` so `pi-install` can tell which output
+//! belongs to which test. A test that panics or runs past its timeout is abandoned and the next
+//! one runs; the kernel's exit code is 1 if any test did not pass.
+//!
+//! A test gets [`DEFAULT_TIMEOUT`] unless it is declared [`with_timeout!`](crate::with_timeout):
+//!
+//! ```ignore
+//! #[test_case]
+//! static T8_LONG: WithTimeout = with_timeout!(t8_long, Duration::from_secs(5));
+//! ```
+//!
+//! The timeout is checked from the ARM timer interrupt, so tests must leave the timer and
+//! interrupts alone; `thread::rpi_preempt_enable` is the exception, it keeps the checks going.
+//! Threads a test leaves behind when it is abandoned are dropped.
+
+#![allow(static_mut_refs)]
+
+use crate::interrupt::{
+    disable_interrupts, enable_interrupts, interrupt_init, register_irq_basic_handler,
+};
+use crate::memory::dev_barrier;
+use crate::println;
+use crate::timer::{clear_irq, timer_get_usec, timer_init};
+use boot_proto::{Frame, TEST_STATUS};
+use core::arch::global_asm;
+use core::ptr::null;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+/// How long a test may run unless it says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Timer ticks between timeout checks: about 8ms.
+const TICK_CYCLES: u32 = 0x400;
+
+global_asm!(
+    include_str!("../asm/testing-asm.S"),
+    SUPER_MODE_NO_INTS = const 0b10011u32 | (1 << 7) | (1 << 6),
+);
+
+unsafe extern "C" {
+    fn test_try(run: extern "C" fn(*const ()), arg: *const (), saved_sp: *mut *const u32) -> u32;
+    fn test_bail(saved_sp: *const u32, status: u32) -> !;
+}
+
+// Where `test_bail` returns to; only valid while `RUNNING`.
+static mut RUNNER_SP: *const u32 = null();
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// When the current test started and how long it may take, in usec.
+static START: AtomicU32 = AtomicU32::new(0);
+static LIMIT: AtomicU32 = AtomicU32::new(0);
+
+/// Something the runner can run: any `#[test_case] fn()`.
+pub trait Testable {
+    fn name(&self) -> &'static str;
+
+    fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
+
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// A `#[test_case]` with a timeout of its own; see [`with_timeout!`](crate::with_timeout).
+pub struct WithTimeout {
+    pub name: &'static str,
+    pub timeout: Duration,
+    pub test: fn(),
+}
+
+impl Testable for WithTimeout {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+}
+
+/// Declares `test`, a `fn()`, as a [`WithTimeout`] that may run for `timeout`, named as the
+/// runner names a plain `#[test_case] fn`.
+#[macro_export]
+macro_rules! with_timeout {
+    ($test:ident, $timeout:expr) => {
+        $crate::testing::WithTimeout {
+            name: concat!(module_path!(), "::", stringify!($test)),
+            timeout: $timeout,
+            test: $test,
+        }
+    };
+}
+
+/// Gives the running test `timeout` from when it started instead of its own.
+pub fn set_timeout(timeout: Duration) {
+    LIMIT.store(timeout.as_micros() as u32, Ordering::Relaxed);
+}
+
+fn send(frame: Frame, payload: &[u8]) {
+    crate::uart::flush();
+    crate::uart::write_bytes(&frame.encode());
+    crate::uart::write_bytes(payload);
+    crate::uart::flush();
+}
+
+// Returns from the `test_try` in `run_tests` with `status`.
+fn abandon(status: TEST_STATUS) -> ! {
+    RUNNING.store(false, Ordering::Relaxed);
+    unsafe { test_bail(RUNNER_SP, status.val()) }
+}
+
+/// Called by the panic handler: a panicking test fails and the runner moves on. Returns if no
+/// test is running.
+pub(crate) fn on_panic() {
+    if RUNNING.load(Ordering::Relaxed) {
+        abandon(TEST_STATUS::FAIL);
+    }
+}
+
+fn timeout_handler(_pc: u32) {
+    unsafe {
+        dev_barrier();
+        clear_irq();
+        dev_barrier();
+    }
+    check_timeout();
+}
+
+/// Abandons the running test if it is past its timeout. Called from the timer interrupt, also
+/// by handlers that take the timer over, like preemption in `crate::thread`.
+pub(crate) fn check_timeout() {
+    let elapsed = timer_get_usec().wrapping_sub(START.load(Ordering::Relaxed));
+    if RUNNING.load(Ordering::Relaxed) && elapsed >= LIMIT.load(Ordering::Relaxed) {
+        abandon(TEST_STATUS::TIMEOUT);
+    }
+}
+
+// Runs inside `test_try`, so `RUNNING` is only set while `RUNNER_SP` is good to return to.
+extern "C" fn run_test(test: *const ()) {
+    let test = unsafe { *(test as *const &dyn Testable) };
+    RUNNING.store(true, Ordering::Relaxed);
+    test.run();
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Runs `test` the way the runner runs one, inside the running test and with its own timeout:
+/// false if it was abandoned, for a panic or that timeout, which leaves interrupts off. Either
+/// way the running test goes on, its timeout as before, and the threads `test` left are still
+/// there for [`crate::thread::rpi_thread_reset`].
+pub fn catch(test: impl Testable) -> bool {
+    let test: &dyn Testable = &test;
+    // Not the running test's timeout any more, but not yet one `test_bail` can return to.
+    let running = RUNNING.swap(false, Ordering::Relaxed);
+    let start = START.load(Ordering::Relaxed);
+    let limit = LIMIT.load(Ordering::Relaxed);
+    START.store(timer_get_usec(), Ordering::Relaxed);
+    set_timeout(test.timeout());
+    unsafe {
+        let outer = RUNNER_SP;
+        let status = test_try(
+            run_test,
+            &test as *const &dyn Testable as *const (),
+            &raw mut RUNNER_SP,
+        );
+        RUNNER_SP = outer;
+        START.store(start, Ordering::Relaxed);
+        LIMIT.store(limit, Ordering::Relaxed);
+        RUNNING.store(running, Ordering::Relaxed);
+        status == TEST_STATUS::PASS.val()
+    }
+}
+
+/// The `test_runner`: runs every test, reporting each one, and sets the exit code.
+pub fn run_tests(tests: &[&dyn Testable]) {
+    unsafe {
+        interrupt_init();
+        register_irq_basic_handler(0, timeout_handler);
+        timer_init(16, TICK_CYCLES);
+    }
+
+    println!("running {} tests", tests.len());
+
+    let mut failed = 0;
+    for (index, test) in tests.iter().enumerate() {
+        let name = test.name();
+        send(
+            Frame::TestStart {
+                index: index as u32,
+                total: tests.len() as u32,
+                len: name.len() as u32,
+            },
+            name.as_bytes(),
+        );
+
+        START.store(timer_get_usec(), Ordering::Relaxed);
+        set_timeout(test.timeout());
+        enable_interrupts();
+        let status = unsafe {
+            test_try(
+                run_test,
+                test as *const &dyn Testable as *const (),
+                &raw mut RUNNER_SP,
+            )
+        };
+        // An abandoned test comes back with interrupts off; a passing one may not.
+        disable_interrupts();
+        let usec = timer_get_usec().wrapping_sub(START.load(Ordering::Relaxed));
+
+        if status != TEST_STATUS::PASS.val() {
+            failed += 1;
+            crate::thread::rpi_thread_reset();
+        }
+        send(
+            Frame::TestResult {
+                index: index as u32,
+                status,
+                usec,
+            },
+            &[],
+        );
+    }
+
+    println!("{} of {} tests failed", failed, tests.len());
+    crate::exit::set_exit_code(if failed == 0 { 0 } else { 1 });
+}
@@ -1,35 +1,103 @@
 #![allow(static_mut_refs)] // TODO: better ways
 
+use crate::arch::wfi;
+use crate::cycle_count::{cycle_cnt_init, cycle_cnt_read};
+use crate::interrupt::{
+    SYS_MODE, cpsr_get, enable_interrupts, interrupt_init, interrupts_installed,
+    interrupts_restore, interrupts_save, register_irq_1_handler, register_irq_basic_handler,
+    spsr_get,
+};
+use crate::memory::dev_barrier;
 use crate::println;
+use crate::timer::{
+    ARM_TIMER_HZ, clear_irq, timer_alarm_clear, timer_alarm_set, timer_get_usec, timer_init,
+};
+use crate::tls::{self, Tls};
+use crate::vfp::{self, VfpState};
 use alloc::boxed::Box;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::global_asm;
+use core::fmt;
 use core::ptr::null;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+mod periodic;
+mod sync;
+
+pub use periodic::{DeadlineStats, rpi_deadline_stats, spawn_periodic};
+pub use sync::{Condvar, Mutex, MutexGuard, Receiver, Semaphore, Sender, channel};
 
 type RPIThreadExecFn = extern "C" fn(*const u32);
 
-const THREAD_MAX_STACKSIZE: usize = (1024 * 8 / 4);
+/// Stack size of threads started by [`rpi_fork`], [`spawn`] and [`spawn_named`], in bytes.
+pub const DEFAULT_STACK_SIZE: usize = 8 * 1024;
+/// Smallest stack [`spawn_with_stack`] takes, in bytes.
+pub const MIN_STACK_SIZE: usize = 256;
+
+// What an unused stack word holds, for the high-water mark.
+const STACK_FILL: u32 = u32::MAX;
+// The `cpsr` bit that masks IRQs.
+const IRQ_DISABLED: u32 = 1 << 7;
+// The lowest word of every stack: a thread that overwrote it overflowed.
+const STACK_GUARD: u32 = 0xDEAD_57AC;
+// The system timer compare that wakes sleepers; `executor` has the other one.
+const SLEEP_ALARM: usize = 3;
 
 static mut THREAD_ID_COUNTER: usize = 1;
 
 pub static mut RUN_Q: VecDeque<Box<RPIThread>> = VecDeque::new();
 static mut FREE_Q: VecDeque<Box<RPIThread>> = VecDeque::new();
 
+// Exit codes of threads that have a handle: `None` until the thread exits.
+static mut EXIT_CODES: BTreeMap<usize, Option<i32>> = BTreeMap::new();
+
 static mut CUR_THREAD: Option<Box<RPIThread>> = None;
+// Sleeping threads and when to wake them, soonest first.
+static mut SLEEP_Q: VecDeque<(u32, Box<RPIThread>)> = VecDeque::new();
+// How many threads are in a `WaitQueue` or `SLEEP_Q`, out of `RUN_Q` until woken.
+static mut BLOCKED: usize = 0;
+// The threads in a `WaitQueue`, for `rpi_threads`. Boxed, so they stay put while in one.
+static mut WAITING: Vec<*const RPIThread> = Vec::new();
+// Bumped by `rpi_thread_reset`: threads that blocked in a `WaitQueue` before then are abandoned,
+// and the queue frees them instead of waking them.
+static mut GENERATION: u32 = 0;
+// The cycle count when `CUR_THREAD` was switched to.
+static mut SWITCHED_IN: u32 = 0;
 static mut SCHEDULER_THREAD: Option<Box<RPIThread>> = None;
 
-global_asm!(include_str!("../asm/rpi-thread-asm.S"));
+// Whether the timer interrupt switches threads, and whether the current slice is up.
+static PREEMPTIVE: AtomicBool = AtomicBool::new(false);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+global_asm!(
+    include_str!("../asm/rpi-thread-asm.S"),
+    SUPER_MODE_NO_INTS = const 0b10011u32 | (1 << 7) | (1 << 6),
+    rpi_preempt = sym rpi_preempt,
+);
 
 unsafe extern "C" {
     fn rpi_init_trampoline();
-    fn rpi_cswitch(old_sp_save: *mut *const u32, new_sp: *const u32);
+    fn rpi_cswitch(old_sp_save: *mut *const u32, new_sp: *const u32, cpsr: u32);
     fn rpi_get_sp() -> *const u32;
 }
 
-#[repr(align(8))]
-#[derive(Clone)]
-struct Align8<T>(pub T);
+/// How the scheduler picks the next thread: the earliest deadline of the `Deadline` threads
+/// first, then the highest priority of the `Priority` threads, then the `Normal` ones. Threads
+/// that rank the same take turns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchedClass {
+    /// Round robin, below every real-time thread. What threads start as.
+    #[default]
+    Normal,
+    /// Fixed priority: higher runs first.
+    Priority(u8),
+    /// Earliest deadline first, by the [`timer_get_usec`] time the current work is due.
+    Deadline(u32),
+}
 
 // #[derive(Clone)]
 pub struct RPIThread {
@@ -39,88 +107,373 @@ pub struct RPIThread {
 
     annot: String,
 
-    stack: Align8<[u32; THREAD_MAX_STACKSIZE]>,
+    class: SchedClass,
+
+    vfp: VfpState,
+
+    // Its `#[thread_local]`s.
+    tls: Tls,
+
+    // How many times it was switched to, and the cycles it ran for until it was last switched
+    // away from.
+    switches: u32,
+    cycles: u64,
+
+    // Lowest word first: `stack[0]` is the guard, and the thread starts at the top.
+    stack: Box<[u32]>,
 }
 
+impl RPIThread {
+    fn new(stack_words: usize) -> Box<Self> {
+        let mut stack = vec![STACK_FILL; stack_words].into_boxed_slice();
+        if let Some(guard) = stack.first_mut() {
+            *guard = STACK_GUARD;
+        }
+        Box::new(RPIThread {
+            saved_sp: null(),
+            thread_id: 0,
+            annot: String::new(),
+            class: SchedClass::Normal,
+            vfp: VfpState::default(),
+            tls: Tls::new(),
+            switches: 0,
+            cycles: 0,
+            stack,
+        })
+    }
+
+    // Panics if the thread, now at `sp`, ran past the bottom of its stack. The scheduler has
+    // none to check.
+    fn check_stack(&self, sp: *const u32) {
+        if self.stack.is_empty() {
+            return;
+        }
+        let bottom = self.stack.as_ptr();
+        if self.stack[0] != STACK_GUARD || sp < bottom {
+            panic!(
+                "thread {} ({:?}) overflowed its {}-byte stack",
+                self.thread_id,
+                self.annot,
+                self.stack.len() * 4
+            );
+        }
+    }
+
+    // Where the thread is in line at `now`: lower runs first.
+    fn rank(&self, now: u32) -> (u8, i64) {
+        match self.class {
+            SchedClass::Deadline(deadline) => (0, deadline.wrapping_sub(now) as i32 as i64),
+            SchedClass::Priority(priority) => (1, -(priority as i64)),
+            SchedClass::Normal => (2, 0),
+        }
+    }
+
+    // The most of its stack the thread has used so far, in bytes: the words above the guard
+    // that no longer hold the fill.
+    fn stack_high_water(&self) -> usize {
+        let unused = self.stack[1..]
+            .iter()
+            .take_while(|&&word| word == STACK_FILL)
+            .count();
+        (self.stack.len() - 1 - unused) * 4
+    }
+
+    // Interrupts must be off.
+    unsafe fn info(&self, state: ThreadState) -> ThreadInfo {
+        let mut cycles = self.cycles;
+        if state == ThreadState::Running {
+            cycles += cycle_cnt_read().wrapping_sub(unsafe { SWITCHED_IN }) as u64;
+        }
+        ThreadInfo {
+            thread_id: self.thread_id,
+            name: self.annot.clone(),
+            state,
+            switches: self.switches,
+            cycles,
+        }
+    }
+}
+
+/// What a thread is doing, in [`ThreadInfo`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    /// Waiting in line to run.
+    Ready,
+    /// On a [`Mutex`], [`Semaphore`], [`Condvar`] or [`channel`], or in [`thread_sleep`].
+    Blocked,
+    /// Gone, until a fork reuses its stack.
+    Exited,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exited => "exited",
+        })
+    }
+}
+
+/// A thread, as [`rpi_threads`] lists it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub thread_id: usize,
+    pub name: String,
+    pub state: ThreadState,
+    /// How many times the scheduler switched to it.
+    pub switches: u32,
+    /// CPU cycles it has run for, from [`cycle_cnt_read`] at each switch. Interrupts taken
+    /// while it ran count too.
+    pub cycles: u64,
+}
+
+/// Runs the threads in `RUN_Q` until none are left to run. Threads blocked on a [`Mutex`],
+/// [`Semaphore`], [`Condvar`] or [`channel`], or in [`thread_sleep`], are waited for, with
+/// interrupts as the caller had them, since an interrupt handler may wake them. While nothing
+/// is runnable the CPU waits for an interrupt.
 pub fn rpi_thread_start() {
+    let cpsr = interrupts_save();
     unsafe {
-        if RUN_Q.is_empty() {
+        if RUN_Q.is_empty() && BLOCKED == 0 {
+            interrupts_restore(cpsr);
             println!("no thread to run");
             return;
         }
 
         // Initialize scheduler thread if needed
         if SCHEDULER_THREAD.is_none() {
-            let sched_thread = RPIThread {
-                saved_sp: null(),
-                thread_id: 0,
-                annot: "scheduler".to_string(),
-                stack: Align8([u32::MAX; THREAD_MAX_STACKSIZE]), // Scheduler thread does not need a stack
-            };
+            // The scheduler runs on the caller's stack.
+            let mut sched_thread = RPIThread::new(0);
+            sched_thread.annot = "scheduler".to_string();
 
-            SCHEDULER_THREAD = Some(Box::new(sched_thread));
+            SCHEDULER_THREAD = Some(sched_thread);
         }
 
-        println!("&RUN_Q={:p}", core::ptr::addr_of!(RUN_Q));
-        println!("RUN_Q size: {}", RUN_Q.len());
+        cycle_cnt_init();
+
+        loop {
+            loop {
+                wake_sleepers();
+                if !RUN_Q.is_empty() {
+                    break;
+                }
+                if BLOCKED == 0 {
+                    interrupts_restore(cpsr);
+                    return;
+                }
+                if cpsr & IRQ_DISABLED == 0 && interrupts_installed() {
+                    // The interrupt that ends the wait is taken here, when they are back on.
+                    if arm_alarm() {
+                        wfi();
+                    }
+                    interrupts_restore(cpsr);
+                    interrupts_save();
+                } else {
+                    // Only a sleeper can still wake up, when its time comes.
+                    assert!(
+                        !SLEEP_Q.is_empty(),
+                        "{} threads blocked with interrupts off",
+                        BLOCKED
+                    );
+                }
+            }
 
-        let next_thread = RUN_Q.pop_front().unwrap();
-        println!("next_thread");
-        let sched = SCHEDULER_THREAD.as_mut().unwrap();
-        let sched_saved_sp_addr: *mut *const u32 = &mut sched.saved_sp;
+            let sched = SCHEDULER_THREAD.as_mut().unwrap();
+            let sched_saved_sp_addr: *mut *const u32 = &mut sched.saved_sp;
+            let next_thread_sp = switch_in(pop_next().unwrap());
+            rpi_cswitch(sched_saved_sp_addr, next_thread_sp, cpsr);
+        }
+    }
+}
 
-        println!("Scheduler stack pointer: {:p}", rpi_get_sp());
+/// A thread started by [`rpi_fork`]. Dropping it detaches the thread.
+pub struct RPIThreadHandle {
+    thread_id: usize,
+}
+
+impl RPIThreadHandle {
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// See [`rpi_stack_high_water`].
+    pub fn stack_high_water(&self) -> Option<usize> {
+        rpi_stack_high_water(self.thread_id)
+    }
 
-        let next_thread_sp = next_thread.saved_sp;
-        CUR_THREAD = Some(next_thread);
-        rpi_cswitch(sched_saved_sp_addr, next_thread_sp)
+    /// See [`rpi_set_class`].
+    pub fn set_class(&self, class: SchedClass) -> bool {
+        rpi_set_class(self.thread_id, class)
     }
+
+    /// See [`rpi_deadline_stats`].
+    pub fn deadline_stats(&self) -> Option<DeadlineStats> {
+        rpi_deadline_stats(self.thread_id)
+    }
+
+    /// Waits for the thread to exit and returns its exit code. A thread waiting yields to the
+    /// others; outside a thread, the threads are run with [`rpi_thread_start`] until it exits.
+    pub fn join(self) -> i32 {
+        assert!(
+            unsafe { CUR_THREAD.as_ref() }.is_none_or(|t| t.thread_id != self.thread_id),
+            "thread {} joining itself",
+            self.thread_id
+        );
+        loop {
+            let cpsr = interrupts_save();
+            let code = unsafe { EXIT_CODES.get(&self.thread_id).copied().flatten() };
+            let in_thread = unsafe { CUR_THREAD.is_some() };
+            interrupts_restore(cpsr);
+
+            match code {
+                Some(code) => return code,
+                None if in_thread => rpi_yield(),
+                None => rpi_thread_start(),
+            }
+        }
+    }
+}
+
+impl Drop for RPIThreadHandle {
+    fn drop(&mut self) {
+        let cpsr = interrupts_save();
+        unsafe { EXIT_CODES.remove(&self.thread_id) };
+        interrupts_restore(cpsr);
+    }
+}
+
+/// Starts `f(arg)` on a new thread, reusing an exited thread's stack if there is one.
+pub fn rpi_fork(f: RPIThreadExecFn, arg: *const u32) -> RPIThreadHandle {
+    fork(f, arg, "", DEFAULT_STACK_SIZE)
+}
+
+/// Starts `f` on a new thread, which exits with code 0 when `f` returns.
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> RPIThreadHandle {
+    spawn_named("", f)
 }
 
-pub fn rpi_fork(f: RPIThreadExecFn, arg: *const u32) {
-    println!("\n\nFORKING....");
-    let mut new_thread = Box::new(RPIThread {
-        saved_sp: null(),
-        thread_id: unsafe { THREAD_ID_COUNTER },
-        annot: "".to_string(),
-        stack: Align8([u32::MAX; THREAD_MAX_STACKSIZE]),
-    });
+/// [`spawn`], with `name` kept as the thread's annotation.
+pub fn spawn_named<F: FnOnce() + Send + 'static>(name: &str, f: F) -> RPIThreadHandle {
+    spawn_with_stack(name, DEFAULT_STACK_SIZE, f)
+}
+
+/// [`spawn_named`], on a stack of `stack_size` bytes, rounded up to a multiple of 8.
+pub fn spawn_with_stack<F: FnOnce() + Send + 'static>(
+    name: &str,
+    stack_size: usize,
+    f: F,
+) -> RPIThreadHandle {
+    extern "C" fn run<F: FnOnce()>(arg: *const u32) {
+        let f = unsafe { Box::from_raw(arg as *mut F) };
+        f();
+    }
+
+    let f = Box::into_raw(Box::new(f));
+    fork(run::<F>, f as *const u32, name, stack_size)
+}
+
+fn fork(f: RPIThreadExecFn, arg: *const u32, name: &str, stack_size: usize) -> RPIThreadHandle {
+    assert!(
+        stack_size >= MIN_STACK_SIZE,
+        "{}-byte stack, the least is {}",
+        stack_size,
+        MIN_STACK_SIZE
+    );
+    let stack_words = stack_size.div_ceil(8) * 2;
+
+    // The exited thread with the smallest stack that is big enough, if there is one. If not,
+    // one of them is freed in exchange for the new thread, so there are never more threads
+    // than ever ran at once.
+    let cpsr = interrupts_save();
+    let free_thread = unsafe {
+        let fits = FREE_Q
+            .iter()
+            .enumerate()
+            .filter(|(_, thread)| thread.stack.len() >= stack_words)
+            .min_by_key(|(_, thread)| thread.stack.len())
+            .map(|(i, _)| i);
+        match fits {
+            Some(i) => FREE_Q.remove(i),
+            None => {
+                drop(FREE_Q.pop_front());
+                None
+            }
+        }
+    };
+    interrupts_restore(cpsr);
+    let mut new_thread = match free_thread {
+        Some(mut thread) => {
+            thread.stack[1..].fill(STACK_FILL);
+            thread.stack[0] = STACK_GUARD;
+            thread.tls.reset();
+            thread
+        }
+        None => RPIThread::new(stack_words),
+    };
+    new_thread.annot.clear();
+    new_thread.annot.push_str(name);
+    new_thread.class = SchedClass::Normal;
+    new_thread.vfp = VfpState::default();
+    new_thread.switches = 0;
+    new_thread.cycles = 0;
 
     unsafe {
+        new_thread.thread_id = THREAD_ID_COUNTER;
         THREAD_ID_COUNTER += 1;
-        let mut sp_now = new_thread.stack.0.as_mut_ptr().add(THREAD_MAX_STACKSIZE);
+        // The stack pointer must be 8-byte aligned, and the heap may give 4.
+        let top = new_thread.stack.as_mut_ptr().add(new_thread.stack.len());
+        let mut sp_now = top.map_addr(|addr| addr & !7);
 
         // Save the trampoline routine to LR
         sp_now = sp_now.sub(1);
-        println!("sp_now = {:p}", sp_now);
         sp_now.write_volatile(rpi_init_trampoline as *const () as u32);
 
         // Move code to r4, arg to r5
         sp_now = sp_now.sub(7);
-        println!("sp_now = {:p}", sp_now);
         sp_now.write_volatile(arg as u32);
 
         sp_now = sp_now.sub(1);
-        println!("sp_now = {:p}", sp_now);
         sp_now.write_volatile(f as u32);
 
         new_thread.as_mut().saved_sp = sp_now;
-        println!(
-            "rpi_fork: tid={}, code={:p}, arg={:p}, saved_sp={:p}",
-            new_thread.thread_id, f, arg, new_thread.saved_sp
-        );
-
-        println!("&RUN_Q={:p}", core::ptr::addr_of!(RUN_Q));
+        let thread_id = new_thread.thread_id;
+        let cpsr = interrupts_save();
+        EXIT_CODES.insert(thread_id, None);
         RUN_Q.push_back(new_thread);
+        interrupts_restore(cpsr);
 
-        println!("sp[1] = {:x}", sp_now.read_volatile());
-        println!("sp[2] = {:x}", sp_now.add(1).read_volatile());
-        println!("lr = {:x}", sp_now.add(9).read_volatile());
-        println!(
-            "lr = {:x}, trampoline = {:p}",
-            sp_now.add(9).read_volatile(),
-            rpi_init_trampoline as *const ()
-        );
+        RPIThreadHandle { thread_id }
+    }
+}
+
+/// Abandons every thread, e.g. the ones a test left behind, freeing their stacks for reuse,
+/// and turns preemption off. Must not be called from a thread.
+///
+/// A thread blocked on a [`Mutex`], [`Semaphore`], [`Condvar`] or [`channel`] is freed when it
+/// would be woken, or with what it waits on; it never runs again.
+pub fn rpi_thread_reset() {
+    rpi_preempt_disable();
+    unsafe {
+        FREE_Q.append(&mut RUN_Q);
+        FREE_Q.extend(CUR_THREAD.take());
+        FREE_Q.extend(SLEEP_Q.drain(..).map(|(_, thread)| thread));
+        for thread in FREE_Q.iter_mut() {
+            vfp::forget(&mut thread.vfp);
+        }
+        // Their queues may be gone, so they stay where they are until woken, if ever.
+        for &thread in WAITING.iter() {
+            vfp::forget(&mut (*thread.cast_mut()).vfp);
+        }
+        GENERATION = GENERATION.wrapping_add(1);
+        vfp::switch_to(None);
+        tls::switch_to(None);
+        EXIT_CODES.clear();
+        BLOCKED = 0;
+        WAITING.clear();
+        periodic::reset();
     }
 }
 
@@ -128,6 +481,84 @@ pub fn rpi_cur_thread_id() -> usize {
     unsafe { CUR_THREAD.as_ref().unwrap().thread_id }
 }
 
+/// The most stack thread `thread_id` has used, in bytes: how deep it has been, not how deep it
+/// is now. `None` once the thread is gone, that is, exited and its stack reused or reset.
+///
+/// Unused stack holds `u32::MAX`, so a thread that pushed only that undercounts.
+pub fn rpi_stack_high_water(thread_id: usize) -> Option<usize> {
+    with_thread(thread_id, true, |thread| thread.stack_high_water())
+}
+
+/// Moves thread `thread_id` to `class`; a thread can move itself. False if the thread is not
+/// running, ready or sleeping: it is blocked, or gone.
+///
+/// The move shows the next time a thread is picked; with [`rpi_preempt_enable`], a thread
+/// that now outranks the running one preempts it at the end of the next interrupt.
+pub fn rpi_set_class(thread_id: usize, class: SchedClass) -> bool {
+    with_thread(thread_id, false, |thread| thread.class = class).is_some()
+}
+
+// Runs `f` on thread `thread_id`, wherever it is but blocked, or exited unless `exited`.
+fn with_thread<R>(
+    thread_id: usize,
+    exited: bool,
+    f: impl FnOnce(&mut RPIThread) -> R,
+) -> Option<R> {
+    let cpsr = interrupts_save();
+    let result = unsafe {
+        CUR_THREAD
+            .iter_mut()
+            .chain(RUN_Q.iter_mut())
+            .chain(SLEEP_Q.iter_mut().map(|(_, thread)| thread))
+            .chain(FREE_Q.iter_mut().filter(|_| exited))
+            .find(|thread| thread.thread_id == thread_id)
+            .map(|thread| f(thread))
+    };
+    interrupts_restore(cpsr);
+    result
+}
+
+/// Every thread, by id: running, ready, blocked, or exited and not yet reused.
+pub fn rpi_threads() -> Vec<ThreadInfo> {
+    let cpsr = interrupts_save();
+    let mut threads: Vec<ThreadInfo> = unsafe {
+        CUR_THREAD
+            .iter()
+            .map(|thread| (&**thread, ThreadState::Running))
+            .chain(RUN_Q.iter().map(|thread| (&**thread, ThreadState::Ready)))
+            .chain(
+                SLEEP_Q
+                    .iter()
+                    .map(|(_, thread)| (&**thread, ThreadState::Blocked)),
+            )
+            .chain(
+                WAITING
+                    .iter()
+                    .map(|&thread| (&*thread, ThreadState::Blocked)),
+            )
+            .chain(FREE_Q.iter().map(|thread| (&**thread, ThreadState::Exited)))
+            .map(|(thread, state)| thread.info(state))
+            .collect()
+    };
+    interrupts_restore(cpsr);
+    threads.sort_by_key(|thread| thread.thread_id);
+    threads
+}
+
+/// Prints [`rpi_threads`] as a table, e.g. to see what a stuck scheduler is waiting for.
+pub fn rpi_print_threads() {
+    println!(
+        "{:>4}  {:<16}  {:<7}  {:>8}  {:>12}",
+        "id", "name", "state", "switches", "cycles"
+    );
+    for thread in rpi_threads() {
+        println!(
+            "{:>4}  {:<16.16}  {:<7}  {:>8}  {:>12}",
+            thread.thread_id, thread.name, thread.state, thread.switches, thread.cycles
+        );
+    }
+}
+
 // pub fn rpi_cur_thread() -> Box<RPIThread> {
 //     unsafe { CUR_THREAD.unwrap() }
 // }
@@ -135,39 +566,319 @@ pub fn rpi_cur_thread_id() -> usize {
 #[unsafe(no_mangle)]
 pub extern "C" fn rpi_exit(exit_code: i32) {
     unsafe {
-        println!("Thread exiting with code {}", exit_code);
+        let cpsr = interrupts_save();
+
+        // The last thread's code is what the kernel reports when it ends. One still sleeping
+        // or blocked may run after us: `BLOCKED` counts both.
+        if RUN_Q.is_empty() && BLOCKED == 0 {
+            crate::exit::set_exit_code(exit_code);
+        }
+
+        let mut previous_thread = take_current().unwrap();
+        if let Some(code) = EXIT_CODES.get_mut(&previous_thread.thread_id) {
+            *code = Some(exit_code);
+        }
+        vfp::forget(&mut previous_thread.vfp);
+        // Free for the next fork, which cannot come before we are off its stack: interrupts
+        // stay off until then.
+        let previous_thread_sp = &raw mut previous_thread.saved_sp;
+        FREE_Q.push_back(previous_thread);
+
+        switch_away(previous_thread_sp, cpsr)
+    }
+}
 
-        let previous_thread = CUR_THREAD.as_mut().unwrap();
-        let previous_thread_sp = &mut previous_thread.saved_sp;
+// Takes the thread to run next out of `RUN_Q`: the first of those that rank best. Interrupts
+// must be off.
+unsafe fn pop_next() -> Option<Box<RPIThread>> {
+    unsafe {
+        let now = timer_get_usec();
+        let (next, _) = RUN_Q
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, thread)| thread.rank(now))?;
+        RUN_Q.remove(next)
+    }
+}
 
-        let next_thread_sp = match RUN_Q.pop_front() {
-            Some(x) => {
-                let next_sp = x.saved_sp.clone();
-                CUR_THREAD = Some(x);
-                next_sp
+// Switches from a thread that is no longer `CUR_THREAD` to the next in `RUN_Q`, or back to
+// the scheduler, which is not a thread and must not be preempted, if `RUN_Q` is empty.
+// Interrupts must be off.
+unsafe fn switch_away(previous_thread_sp: *mut *const u32, cpsr: u32) {
+    unsafe {
+        wake_sleepers();
+        let next_thread_sp = match pop_next() {
+            Some(x) => switch_in(x),
+            None => {
+                vfp::switch_to(None);
+                tls::switch_to(None);
+                SCHEDULER_THREAD.as_mut().unwrap().saved_sp
             }
-            None => SCHEDULER_THREAD.as_mut().unwrap().saved_sp.clone(),
         };
 
-        rpi_cswitch(previous_thread_sp, next_thread_sp)
+        rpi_cswitch(previous_thread_sp, next_thread_sp, cpsr)
     }
 }
 
-pub fn rpi_yield() {
+// Makes `thread` the current thread, counting the switch, and returns its stack pointer for
+// `rpi_cswitch`. Interrupts must be off.
+unsafe fn switch_in(mut thread: Box<RPIThread>) -> *const u32 {
     unsafe {
-        if RUN_Q.is_empty() {
-            return;
+        thread.switches += 1;
+        let thread_sp = thread.saved_sp;
+        CUR_THREAD = Some(thread);
+        vfp::switch_to(CUR_THREAD.as_mut().map(|thread| &mut thread.vfp));
+        tls::switch_to(CUR_THREAD.as_mut().map(|thread| &mut thread.tls));
+        SWITCHED_IN = cycle_cnt_read();
+        thread_sp
+    }
+}
+
+// Takes the current thread, if there is one, off the CPU: checks its stack and adds up the
+// cycles it ran for. Interrupts must be off.
+unsafe fn take_current() -> Option<Box<RPIThread>> {
+    unsafe {
+        let mut thread = CUR_THREAD.take()?;
+        thread.check_stack(rpi_get_sp());
+        thread.cycles += cycle_cnt_read().wrapping_sub(SWITCHED_IN) as u64;
+        Some(thread)
+    }
+}
+
+/// Threads blocked until something wakes them, in the order they blocked. Only touched with
+/// interrupts off, so an interrupt handler can wake them.
+pub(crate) struct WaitQueue {
+    // With the `GENERATION` each blocked in.
+    threads: VecDeque<(u32, Box<RPIThread>)>,
+}
+
+// The threads in it are only run by the scheduler.
+unsafe impl Send for WaitQueue {}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            threads: VecDeque::new(),
+        }
+    }
+
+    /// Blocks the current thread until [`wake_one`](Self::wake_one) or
+    /// [`wake_all`](Self::wake_all). Outside a thread, runs the threads once instead. Either
+    /// way it may return before what the caller waits for has happened, so check again.
+    ///
+    /// Interrupts must be off, with `cpsr` from before; they are off again on return. `queue`
+    /// is a pointer so that no reference to it, or to what holds it, lives while the thread
+    /// is away: the threads that run meanwhile take their own. It is not used after the switch.
+    pub(crate) unsafe fn block(queue: *mut WaitQueue, cpsr: u32) {
+        assert!(
+            cpsr_get() != SYS_MODE::IRQ.val(),
+            "blocking in an interrupt handler"
+        );
+        unsafe {
+            match take_current() {
+                Some(mut thread) => {
+                    let thread_sp = &raw mut thread.saved_sp;
+                    WAITING.push(&*thread);
+                    (*queue).threads.push_back((GENERATION, thread));
+                    BLOCKED += 1;
+                    switch_away(thread_sp, cpsr);
+                }
+                None => {
+                    interrupts_restore(cpsr);
+                    if RUN_Q.is_empty() && BLOCKED == 0 {
+                        core::hint::spin_loop();
+                    } else {
+                        rpi_thread_start();
+                    }
+                    interrupts_save();
+                }
+            }
+        }
+    }
+
+    /// Moves the longest-blocked thread to `RUN_Q`; false if there is none. Threads abandoned
+    /// by [`rpi_thread_reset`] are freed on the way. Interrupts must be off.
+    pub(crate) unsafe fn wake_one(&mut self) -> bool {
+        unsafe {
+            while let Some((generation, thread)) = self.threads.pop_front() {
+                if generation != GENERATION {
+                    FREE_Q.push_back(thread);
+                    continue;
+                }
+                let waiting: *const RPIThread = &*thread;
+                WAITING.retain(|&other| other != waiting);
+                BLOCKED -= 1;
+                RUN_Q.push_back(thread);
+                return true;
+            }
         }
+        false
+    }
 
-        let mut previous_thread = CUR_THREAD.take().unwrap();
+    /// Moves every blocked thread to `RUN_Q`. Interrupts must be off.
+    pub(crate) unsafe fn wake_all(&mut self) {
+        while unsafe { self.wake_one() } {}
+    }
+}
+
+// Puts the current thread at the back of `RUN_Q` and switches to the next one, which may be
+// the same thread. Interrupts must be off; `cpsr` is what a thread that never ran starts with.
+unsafe fn switch_to_next(cpsr: u32) {
+    unsafe {
+        let mut previous_thread = take_current().unwrap();
         let previous_thread_sp = &raw mut previous_thread.saved_sp;
 
         RUN_Q.push_back(previous_thread);
 
-        let next_thread = RUN_Q.pop_front().unwrap();
-        let next_thread_sp = next_thread.saved_sp;
-        CUR_THREAD = Some(next_thread);
+        let next_thread_sp = switch_in(pop_next().unwrap());
+
+        rpi_cswitch(previous_thread_sp, next_thread_sp, cpsr)
+    }
+}
+
+/// Blocks the current thread for at least `duration`, running the others. Outside a thread,
+/// waits for it without running any.
+pub fn thread_sleep(duration: Duration) {
+    thread_sleep_until(timer_get_usec().wrapping_add(duration.as_micros() as u32));
+}
+
+/// [`thread_sleep`] until [`timer_get_usec`] reaches `deadline`, less than 2^31 usec away.
+pub fn thread_sleep_until(deadline: u32) {
+    let cpsr = interrupts_save();
+    unsafe {
+        let Some(mut thread) = take_current() else {
+            interrupts_restore(cpsr);
+            while !is_due(deadline, timer_get_usec()) {}
+            return;
+        };
+        let thread_sp = &raw mut thread.saved_sp;
+        let at = SLEEP_Q
+            .iter()
+            .position(|&(other, _)| !is_due(other, deadline))
+            .unwrap_or(SLEEP_Q.len());
+        SLEEP_Q.insert(at, (deadline, thread));
+        BLOCKED += 1;
+        arm_alarm();
+        switch_away(thread_sp, cpsr);
+    }
+    interrupts_restore(cpsr);
+}
+
+// Whether `now` has reached `deadline`, for times less than 2^31 usec apart.
+pub(crate) fn is_due(deadline: u32, now: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+// Moves the sleepers whose time has come to `RUN_Q`. Interrupts must be off.
+unsafe fn wake_sleepers() {
+    unsafe {
+        let now = timer_get_usec();
+        while SLEEP_Q
+            .front()
+            .is_some_and(|&(deadline, _)| is_due(deadline, now))
+        {
+            let (_, thread) = SLEEP_Q.pop_front().unwrap();
+            BLOCKED -= 1;
+            RUN_Q.push_back(thread);
+        }
+    }
+}
+
+// Sets the alarm interrupt for the first sleeper, if there is one and the interrupt table is
+// there to take it. False if that sleeper is already due, and will not get its interrupt.
+// Interrupts must be off.
+unsafe fn arm_alarm() -> bool {
+    unsafe {
+        let Some(&(deadline, _)) = SLEEP_Q.front() else {
+            return true;
+        };
+        if interrupts_installed() {
+            register_irq_1_handler(SLEEP_ALARM, alarm_tick);
+            timer_alarm_set(SLEEP_ALARM, deadline);
+        }
+        !is_due(deadline, timer_get_usec())
+    }
+}
+
+// Waking the sleepers is left to `rpi_preempt_pending`, after every interrupt.
+fn alarm_tick(_pc: u32) {
+    unsafe { timer_alarm_clear(SLEEP_ALARM) };
+}
+
+pub fn rpi_yield() {
+    let cpsr = interrupts_save();
+    unsafe {
+        wake_sleepers();
+        if !RUN_Q.is_empty() {
+            switch_to_next(cpsr);
+        }
+    }
+    interrupts_restore(cpsr);
+}
+
+/// Also switches threads every `slice`, from the ARM timer interrupt, so a thread that never
+/// yields cannot starve the others. `rpi_yield` and `rpi_exit` work as before.
+///
+/// Takes over the ARM timer; a `crab_pi::testing` run still gets its timeout checks from it.
+/// Installs the interrupt table if `interrupt_init` has not been called, and leaves interrupts
+/// enabled.
+pub fn rpi_preempt_enable(slice: Duration) {
+    unsafe {
+        if !interrupts_installed() {
+            interrupt_init();
+        }
+        register_irq_basic_handler(0, preempt_tick);
+        let cycles = slice.as_micros() as u64 * ARM_TIMER_HZ as u64 / 1_000_000;
+        timer_init(1, cycles.clamp(1, u32::MAX as u64) as u32);
+    }
+    PREEMPTIVE.store(true, Ordering::Relaxed);
+    enable_interrupts();
+}
+
+/// Back to switching only in `rpi_yield` and `rpi_exit`. The timer keeps running.
+pub fn rpi_preempt_disable() {
+    PREEMPTIVE.store(false, Ordering::Relaxed);
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+}
 
-        rpi_cswitch(previous_thread_sp, next_thread_sp)
+fn preempt_tick(_pc: u32) {
+    unsafe {
+        dev_barrier();
+        clear_irq();
+        dev_barrier();
     }
+
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+    crate::testing::check_timeout();
+}
+
+/// Asked at the end of every interrupt: true to preempt the interrupted code. It must be a
+/// thread (the scheduler is not one) running in super mode, with a ready thread that outranks
+/// it, or ranks the same once its slice is up.
+///
+/// Sleepers whose time has come are woken first, whatever the interrupt was.
+pub(crate) fn rpi_preempt_pending() -> bool {
+    unsafe { wake_sleepers() };
+    if !PREEMPTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let slice_up = NEED_RESCHED.swap(false, Ordering::Relaxed);
+    let Some(current) = (unsafe { CUR_THREAD.as_ref() }) else {
+        return false;
+    };
+    if spsr_get() != SYS_MODE::SVC.val() {
+        return false;
+    }
+    let now = timer_get_usec();
+    let rank = current.rank(now);
+    unsafe { RUN_Q.iter() }
+        .map(|thread| thread.rank(now))
+        .min()
+        .is_some_and(|best| best < rank || slice_up && best == rank)
+}
+
+// Called by `rpi_preempt_asm` on the preempted thread's stack, with interrupts off and its
+// `cpsr`. Returns when something switches back to the thread.
+extern "C" fn rpi_preempt(cpsr: u32) {
+    unsafe { switch_to_next(cpsr) }
 }
@@ -0,0 +1,100 @@
+//! Periodic real-time tasks, scheduled earliest deadline first.
+//!
+//! A task runs one job per period, released at the start of the period and due at its end.
+//! Jobs that finish late, and jobs that run longer than the task's budget, are counted, not
+//! stopped: control loops can run next to `Normal` threads, which only get what is left.
+
+#![allow(static_mut_refs)]
+
+use super::{
+    RPIThreadHandle, SchedClass, is_due, rpi_cur_thread_id, rpi_set_class, spawn_named,
+    thread_sleep_until,
+};
+use crate::interrupt::{interrupts_restore, interrupts_save};
+use crate::timer::timer_get_usec;
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+/// How a periodic task has kept up, from [`rpi_deadline_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeadlineStats {
+    /// Jobs finished.
+    pub jobs: u32,
+    /// Jobs that finished after the end of their period.
+    pub misses: u32,
+    /// Jobs that took longer than the budget from starting to finishing, preemptions included.
+    pub overruns: u32,
+    /// The longest from a release to its job finishing, in usec.
+    pub worst_response_usec: u32,
+}
+
+// Kept past the task's exit, until `rpi_thread_reset`.
+static mut DEADLINE_STATS: BTreeMap<usize, DeadlineStats> = BTreeMap::new();
+
+/// Starts a thread that calls `job` once every `period`, from when it first runs, until it
+/// returns false. Each job is due by the end of its period and expected to take at most `budget`.
+pub fn spawn_periodic<F: FnMut() -> bool + Send + 'static>(
+    name: &str,
+    period: Duration,
+    budget: Duration,
+    mut job: F,
+) -> RPIThreadHandle {
+    assert!(budget <= period, "budget over the period");
+    let period = period.as_micros() as u32;
+    let budget = budget.as_micros() as u32;
+    let handle = spawn_named(name, move || {
+        let thread_id = rpi_cur_thread_id();
+        let mut release = timer_get_usec();
+        loop {
+            let deadline = release.wrapping_add(period);
+            let start = timer_get_usec();
+            let more = job();
+            let end = timer_get_usec();
+
+            record(thread_id, |stats| {
+                stats.jobs += 1;
+                if !is_due(end, deadline) {
+                    stats.misses += 1;
+                }
+                if end.wrapping_sub(start) > budget {
+                    stats.overruns += 1;
+                }
+                stats.worst_response_usec =
+                    stats.worst_response_usec.max(end.wrapping_sub(release));
+            });
+            if !more {
+                break;
+            }
+
+            // Ranked by the next deadline while waiting for the release, so the wake-up
+            // preempts what it should.
+            release = deadline;
+            rpi_set_class(
+                thread_id,
+                SchedClass::Deadline(release.wrapping_add(period)),
+            );
+            thread_sleep_until(release);
+        }
+    });
+    record(handle.thread_id(), |_| {});
+    handle.set_class(SchedClass::Deadline(timer_get_usec().wrapping_add(period)));
+    handle
+}
+
+/// How periodic task `thread_id` has kept up so far. `None` if it is not one.
+pub fn rpi_deadline_stats(thread_id: usize) -> Option<DeadlineStats> {
+    let cpsr = interrupts_save();
+    let stats = unsafe { DEADLINE_STATS.get(&thread_id).copied() };
+    interrupts_restore(cpsr);
+    stats
+}
+
+fn record(thread_id: usize, f: impl FnOnce(&mut DeadlineStats)) {
+    let cpsr = interrupts_save();
+    f(unsafe { DEADLINE_STATS.entry(thread_id).or_default() });
+    interrupts_restore(cpsr);
+}
+
+pub(super) fn reset() {
+    unsafe { DEADLINE_STATS.clear() };
+}
@@ -0,0 +1,362 @@
+//! Blocking synchronization between threads.
+//!
+//! A thread that has to wait is taken out of `RUN_Q` and put on the object's [`WaitQueue`]
+//! until something wakes it. The state of every object is only touched with interrupts off,
+//! so an interrupt handler may post a [`Semaphore`], notify a [`Condvar`] or `try_send` and
+//! `try_recv` on a [`channel`]. It must not block: that panics.
+//!
+//! Outside a thread, waiting runs the threads with [`rpi_thread_start`](super::rpi_thread_start)
+//! until the wait is over.
+
+use super::WaitQueue;
+use crate::interrupt::{IrqCell, interrupts_restore, interrupts_save};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+/// A lock around `T` whose waiters block instead of spinning.
+pub struct Mutex<T> {
+    state: IrqCell<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// Access to the value of a locked [`Mutex`]; dropping it unlocks.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: IrqCell::new(MutexState {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks, waiting for the thread holding the lock to unlock it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let cpsr = interrupts_save();
+        unsafe { self.lock_off(cpsr) };
+        interrupts_restore(cpsr);
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks if nobody holds the lock.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let locked = self
+            .state
+            .with(|state| !core::mem::replace(&mut state.locked, true));
+        locked.then_some(MutexGuard { mutex: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    // `lock` with interrupts already off. Each pass takes `state` afresh: the one from before
+    // blocking is dead, since other threads changed it.
+    unsafe fn lock_off(&self, cpsr: u32) {
+        loop {
+            let state = unsafe { self.state.get() };
+            if !state.locked {
+                state.locked = true;
+                return;
+            }
+            unsafe { WaitQueue::block(&raw mut state.waiters, cpsr) };
+        }
+    }
+
+    // Interrupts must be off.
+    unsafe fn unlock_off(&self) {
+        let state = unsafe { self.state.get() };
+        state.locked = false;
+        unsafe { state.waiters.wake_one() };
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let cpsr = interrupts_save();
+        unsafe { self.mutex.unlock_off() };
+        interrupts_restore(cpsr);
+    }
+}
+
+struct SemaphoreState {
+    count: usize,
+    waiters: WaitQueue,
+}
+
+/// A counting semaphore.
+pub struct Semaphore {
+    state: IrqCell<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            state: IrqCell::new(SemaphoreState {
+                count,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Takes one, waiting for a [`post`](Self::post) if there is none.
+    pub fn wait(&self) {
+        let cpsr = interrupts_save();
+        loop {
+            let state = unsafe { self.state.get() };
+            if state.count > 0 {
+                state.count -= 1;
+                break;
+            }
+            unsafe { WaitQueue::block(&raw mut state.waiters, cpsr) };
+        }
+        interrupts_restore(cpsr);
+    }
+
+    /// Takes one if there is one.
+    pub fn try_wait(&self) -> bool {
+        self.state.with(|state| match state.count {
+            0 => false,
+            _ => {
+                state.count -= 1;
+                true
+            }
+        })
+    }
+
+    /// Gives one back, waking a waiter. Can be called from an interrupt handler.
+    pub fn post(&self) {
+        self.state.with(|state| {
+            state.count += 1;
+            unsafe { state.waiters.wake_one() };
+        });
+    }
+
+    pub fn count(&self) -> usize {
+        self.state.with(|state| state.count)
+    }
+}
+
+/// Waits for a condition on the value of a [`Mutex`].
+pub struct Condvar {
+    waiters: IrqCell<WaitQueue>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: IrqCell::new(WaitQueue::new()),
+        }
+    }
+
+    /// Unlocks `guard`'s mutex and waits for a notification, then locks it again. A
+    /// notification can come before the condition holds, so check it in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        core::mem::forget(guard);
+
+        // Nothing can notify between the unlock and blocking.
+        let cpsr = interrupts_save();
+        unsafe {
+            mutex.unlock_off();
+            WaitQueue::block(self.waiters.get(), cpsr);
+            mutex.lock_off(cpsr);
+        }
+        interrupts_restore(cpsr);
+        MutexGuard { mutex }
+    }
+
+    /// Wakes the longest waiter. Can be called from an interrupt handler.
+    pub fn notify_one(&self) {
+        self.waiters.with(|waiters| unsafe { waiters.wake_one() });
+    }
+
+    /// Wakes every waiter. Can be called from an interrupt handler.
+    pub fn notify_all(&self) {
+        self.waiters.with(|waiters| unsafe { waiters.wake_all() });
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    // Waiting for room, and for a value.
+    not_full: WaitQueue,
+    not_empty: WaitQueue,
+}
+
+struct Channel<T> {
+    state: IrqCell<ChannelState<T>>,
+}
+
+/// Sends to a [`channel`]. Clone it for more senders.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Receives from a [`channel`]. Clone it for more receivers: each value goes to one of them.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// A queue of at most `capacity` values between any number of senders and receivers.
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel without room");
+    let channel = Arc::new(Channel {
+        state: IrqCell::new(ChannelState {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receivers: 1,
+            not_full: WaitQueue::new(),
+            not_empty: WaitQueue::new(),
+        }),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room. Gives it back if every receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let cpsr = interrupts_save();
+        let result = loop {
+            let state = unsafe { self.channel.state.get() };
+            if state.receivers == 0 {
+                break Err(value);
+            }
+            if state.queue.len() < state.capacity {
+                state.queue.push_back(value);
+                unsafe { state.not_empty.wake_one() };
+                break Ok(());
+            }
+            unsafe { WaitQueue::block(&raw mut state.not_full, cpsr) };
+        };
+        interrupts_restore(cpsr);
+        result
+    }
+
+    /// Sends `value` if there is room. Gives it back if not, or if every receiver is gone. Can
+    /// be called from an interrupt handler.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.channel.state.with(|state| {
+            if state.receivers == 0 || state.queue.len() == state.capacity {
+                return Err(value);
+            }
+            state.queue.push_back(value);
+            unsafe { state.not_empty.wake_one() };
+            Ok(())
+        })
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives a value, waiting for one. `None` once it is empty and every sender is gone.
+    pub fn recv(&self) -> Option<T> {
+        let cpsr = interrupts_save();
+        let value = loop {
+            let state = unsafe { self.channel.state.get() };
+            if let Some(value) = state.queue.pop_front() {
+                unsafe { state.not_full.wake_one() };
+                break Some(value);
+            }
+            if state.senders == 0 {
+                break None;
+            }
+            unsafe { WaitQueue::block(&raw mut state.not_empty, cpsr) };
+        };
+        interrupts_restore(cpsr);
+        value
+    }
+
+    /// Receives a value if there is one. Can be called from an interrupt handler.
+    pub fn try_recv(&self) -> Option<T> {
+        self.channel.state.with(|state| {
+            let value = state.queue.pop_front()?;
+            unsafe { state.not_full.wake_one() };
+            Some(value)
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.with(|state| state.senders += 1);
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.with(|state| state.receivers += 1);
+        Receiver {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+// The last of either side wakes everyone on the other, to find it gone.
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.state.with(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                unsafe { state.not_empty.wake_all() };
+            }
+        });
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.with(|state| {
+            state.receivers -= 1;
+            if state.receivers == 0 {
+                unsafe { state.not_full.wake_all() };
+            }
+        });
+    }
+}
@@ -2,13 +2,20 @@ use crate::interrupt::IRQ_REG;
 use crate::memory::dev_barrier;
 use crate::println;
 use crate::timer::ARM_TIMER::ARM_TIMER_CONTROL;
-use core::ptr::with_exposed_provenance;
+use core::ptr::{with_exposed_provenance, with_exposed_provenance_mut};
 use core::time::Duration;
 use macros::{enum_ptr, enum_u32};
 
 const ARM_TIMER_BASE: u32 = 0x2000_B400;
 const ARM_TIMER_IRQ: u32 = 1 << 0;
 const ARM_TIMER_CURRENT: *const u32 = with_exposed_provenance(0x2000_3004);
+// The free-running system timer's match status and first compare register.
+const SYS_TIMER_CS: u32 = 0x2000_3000;
+const SYS_TIMER_C0: u32 = 0x2000_300C;
+
+/// ARM timer ticks per second at prescale 1: the 250MHz APB clock over the reset predivider
+/// (126).
+pub const ARM_TIMER_HZ: u32 = 250_000_000 / 126;
 
 enum_ptr! {
     pub enum ARM_TIMER {
@@ -93,3 +100,47 @@ pub unsafe fn timer_init(prescale: u32, ncycles: u32) {
 
     dev_barrier();
 }
+
+/// Stops the ARM timer and masks its interrupt.
+pub unsafe fn timer_stop() {
+    dev_barrier();
+
+    unsafe {
+        ARM_TIMER_CONTROL.as_mut_ptr::<u32>().write_volatile(0);
+        IRQ_REG::DISABLE_BASIC
+            .as_mut_ptr::<u32>()
+            .write_volatile(ARM_TIMER_IRQ);
+    }
+
+    dev_barrier();
+}
+
+/// Raises `IRQ_1` interrupt `alarm` when [`timer_get_usec`] reaches `usec`, which must be in
+/// the future: a time already past only comes round again after 2^32 usec.
+///
+/// `alarm` is the system timer compare to use, 1 or 3: the GPU has 0 and 2.
+pub unsafe fn timer_alarm_set(alarm: usize, usec: u32) {
+    assert!(
+        alarm == 1 || alarm == 3,
+        "system timer compare {} is the GPU's",
+        alarm
+    );
+    dev_barrier();
+
+    unsafe {
+        with_exposed_provenance_mut::<u32>(SYS_TIMER_C0 as usize + 4 * alarm).write_volatile(usec);
+        with_exposed_provenance_mut::<u32>(SYS_TIMER_CS as usize).write_volatile(1 << alarm);
+        IRQ_REG::ENABLE_1
+            .as_mut_ptr::<u32>()
+            .write_volatile(1 << alarm);
+    }
+
+    dev_barrier();
+}
+
+/// Acknowledges the interrupt of `alarm`.
+pub unsafe fn timer_alarm_clear(alarm: usize) {
+    dev_barrier();
+    unsafe { with_exposed_provenance_mut::<u32>(SYS_TIMER_CS as usize).write_volatile(1 << alarm) };
+    dev_barrier();
+}
@@ -0,0 +1,132 @@
+//! Thread-local storage, for `#[thread_local]` statics.
+//!
+//! Every thread has a TLS block laid out the ARM EABI way: TPIDRURO, the thread pointer,
+//! points at an 8-byte control block, followed by the thread's copy of `.tdata` and `.tbss`,
+//! aligned as the linker laid them out. A `#[thread_local]` is read at the thread pointer plus
+//! the offset the linker gave it, so the pointer must be set before any is touched: the kernel
+//! outside threads has its block in `.bss`, set up by `__kernel_start`.
+//!
+//! TPIDRURW, the register user code may write, belongs to the thread too: it is kept across
+//! switches.
+
+#![allow(static_mut_refs)]
+
+use alloc::alloc::{alloc, handle_alloc_error};
+use core::alloc::Layout;
+use core::arch::asm;
+use core::ptr::null_mut;
+
+unsafe extern "C" {
+    safe static __tdata_start__: [u8; 0];
+    safe static __tdata_end__: [u8; 0];
+    // Absolute symbols: their address is the value.
+    safe static __tls_align__: [u8; 0];
+    safe static __tls_size__: [u8; 0];
+    static mut __tls_kernel__: [u8; 0];
+}
+
+// The ARM control block, before the variables.
+const TCB_SIZE: usize = 8;
+
+/// A thread's TLS block, and its TPIDRURW while it is switched out.
+pub(crate) struct Tls {
+    block: *mut u8,
+    user_rw: u32,
+}
+
+// Whose TLS is in the registers: null for the kernel's.
+static mut CURRENT: *mut Tls = null_mut();
+static mut KERNEL_USER_RW: u32 = 0;
+
+fn block_align() -> usize {
+    (&raw const __tls_align__).addr()
+}
+
+// The variables, `.tdata` then `.tbss`, in bytes.
+fn block_size() -> usize {
+    (&raw const __tls_size__).addr()
+}
+
+// Where the variables start in a block.
+fn vars_offset() -> usize {
+    TCB_SIZE.next_multiple_of(block_align())
+}
+
+// Fills the variables of `block` with their initial values.
+unsafe fn init_block(block: *mut u8) {
+    let tdata = &raw const __tdata_start__ as *const u8;
+    let tdata_len = (&raw const __tdata_end__).addr() - tdata.addr();
+    unsafe {
+        let vars = block.add(vars_offset());
+        core::ptr::copy_nonoverlapping(tdata, vars, tdata_len);
+        core::ptr::write_bytes(vars.add(tdata_len), 0, block_size() - tdata_len);
+    }
+}
+
+fn thread_pointer_set(block: *mut u8, user_rw: u32) {
+    unsafe {
+        asm!(
+            "mcr p15, 0, {tp}, c13, c0, 3",
+            "mcr p15, 0, {rw}, c13, c0, 2",
+            tp = in(reg) block,
+            rw = in(reg) user_rw,
+        )
+    };
+}
+
+fn user_rw_get() -> u32 {
+    let user_rw: u32;
+    unsafe { asm!("mrc p15, 0, {}, c13, c0, 2", out(reg) user_rw) };
+    user_rw
+}
+
+impl Tls {
+    /// A block with every `#[thread_local]` at its initial value.
+    pub(crate) fn new() -> Self {
+        let layout = Layout::from_size_align(vars_offset() + block_size(), block_align()).unwrap();
+        let block = unsafe { alloc(layout) };
+        if block.is_null() {
+            handle_alloc_error(layout);
+        }
+        unsafe { init_block(block) };
+        Tls { block, user_rw: 0 }
+    }
+
+    /// Back to how [`new`](Self::new) left it, for a new thread.
+    pub(crate) fn reset(&mut self) {
+        unsafe { init_block(self.block) };
+        self.user_rw = 0;
+    }
+}
+
+/// Sets up the kernel's block and switches to it. Called by `__kernel_start`, before anything
+/// can touch a `#[thread_local]`.
+pub(crate) unsafe fn init_kernel() {
+    unsafe {
+        init_block(&raw mut __tls_kernel__ as *mut u8);
+        CURRENT = null_mut();
+    }
+    thread_pointer_set(&raw mut __tls_kernel__ as *mut u8, 0);
+}
+
+/// Switches to `tls`, or the kernel's for `None`, keeping TPIDRURW for the one switched from.
+/// Interrupts must be off.
+pub(crate) unsafe fn switch_to(tls: Option<&mut Tls>) {
+    unsafe {
+        let user_rw = user_rw_get();
+        match CURRENT.as_mut() {
+            Some(current) => current.user_rw = user_rw,
+            None => KERNEL_USER_RW = user_rw,
+        }
+        match tls {
+            Some(tls) => {
+                thread_pointer_set(tls.block, tls.user_rw);
+                CURRENT = tls;
+            }
+            None => {
+                thread_pointer_set(&raw mut __tls_kernel__ as *mut u8, KERNEL_USER_RW);
+                CURRENT = null_mut();
+            }
+        }
+    }
+}
@@ -6,6 +6,12 @@ use macros::enum_ptr;
 
 const AUX_BASE_ADDR: u32 = 0x2021_5000;
 
+/// The `IRQ_1` interrupt of the AUX peripherals, the mini UART's among them.
+pub const AUX_IRQ: usize = 29;
+// Receive interrupt enable. The datasheet has the enable bits swapped and 3:2 as don't care,
+// but they are needed for the interrupt to reach the ARM.
+const AUX_MU_IER_RX: u32 = 0b101;
+
 enum_ptr! {
     pub enum AUX_REG {
         AUX_IRQ = AUX_BASE_ADDR, /* size = 3 */
@@ -146,6 +152,27 @@ pub fn can_read_timeout(timeout: Duration) -> bool {
     false
 }
 
+/// Raises [`AUX_IRQ`] while there is a byte to read.
+pub fn rx_interrupt_enable() {
+    dev_barrier();
+    unsafe {
+        AUX_REG::AUX_MU_IER_REG
+            .as_mut_ptr::<u32>()
+            .write_volatile(AUX_MU_IER_RX);
+    }
+    dev_barrier();
+}
+
+pub fn rx_interrupt_disable() {
+    dev_barrier();
+    unsafe {
+        AUX_REG::AUX_MU_IER_REG
+            .as_mut_ptr::<u32>()
+            .write_volatile(0)
+    };
+    dev_barrier();
+}
+
 // TODO: Implement STD IO
 pub fn write_bytes(bytes: &[u8]) {
     dsb();
@@ -170,3 +197,19 @@ pub fn read_bytes(bytes: &mut [u8]) {
     }
     dsb();
 }
+
+// Same as `read_bytes`, but gives up if no byte shows up within `timeout`.
+// Returns false on timeout; `bytes` is then only partially filled.
+pub fn read_bytes_timeout(bytes: &mut [u8], timeout: Duration) -> bool {
+    dsb();
+    let io_reg = AUX_REG::AUX_MU_IO_REG.as_ptr::<u32>();
+    for byte in bytes {
+        if !can_read_timeout(timeout) {
+            dsb();
+            return false;
+        }
+        unsafe { *byte = io_reg.read_volatile() as u8 };
+    }
+    dsb();
+    true
+}
@@ -0,0 +1,131 @@
+//! The VFP, switched lazily between threads.
+//!
+//! `_start` turns the VFP on. From then on it is off for every context but the one whose
+//! registers it holds, its owner: the first VFP instruction another context runs is undefined,
+//! and the handler saves the owner's registers, loads the context's own, and runs the
+//! instruction again. Code that never touches floats never pays for a switch.
+//!
+//! A context is a thread, the kernel outside threads, or an interrupt handler. The handler's
+//! registers are not kept from one interrupt to the next.
+
+#![allow(static_mut_refs)]
+
+use core::arch::{asm, global_asm};
+use core::ptr::{null_mut, with_exposed_provenance};
+
+global_asm!(include_str!("../asm/vfp-asm.S"));
+
+unsafe extern "C" {
+    fn vfp_save(state: *mut VfpState);
+    fn vfp_restore(state: *const VfpState);
+}
+
+// FPEXC.EN: the VFP is on.
+const FPEXC_EN: u32 = 1 << 30;
+
+/// The registers of one context.
+#[repr(C)]
+#[derive(Clone, Default)]
+pub(crate) struct VfpState {
+    d: [u64; 16],
+    fpscr: u32,
+}
+
+static mut KERNEL: VfpState = VfpState {
+    d: [0; 16],
+    fpscr: 0,
+};
+static mut IRQ: VfpState = VfpState {
+    d: [0; 16],
+    fpscr: 0,
+};
+// Whose registers the VFP holds, if anyone's, and who is running. The kernel is both at boot.
+static mut OWNER: *mut VfpState = &raw mut KERNEL;
+static mut CURRENT: *mut VfpState = &raw mut KERNEL;
+// Who the interrupt interrupted.
+static mut INTERRUPTED: *mut VfpState = null_mut();
+
+fn fpexc_get() -> u32 {
+    let fpexc: u32;
+    unsafe { asm!("vmrs {}, fpexc", out(reg) fpexc) };
+    fpexc
+}
+
+fn fpexc_set(fpexc: u32) {
+    unsafe { asm!("vmsr fpexc, {}", in(reg) fpexc) };
+}
+
+// Turns the VFP on for `CURRENT` if it owns it, off otherwise.
+unsafe fn enable_for_current() {
+    let fpexc = fpexc_get() & !FPEXC_EN;
+    if unsafe { OWNER == CURRENT } {
+        fpexc_set(fpexc | FPEXC_EN);
+    } else {
+        fpexc_set(fpexc);
+    }
+}
+
+/// Switches to the context of `state`, or the kernel's for `None`. Interrupts must be off.
+pub(crate) unsafe fn switch_to(state: Option<&mut VfpState>) {
+    unsafe {
+        CURRENT = match state {
+            Some(state) => state,
+            None => &raw mut KERNEL,
+        };
+        enable_for_current();
+    }
+}
+
+/// The context of `state` is gone: its registers need not be saved.
+pub(crate) unsafe fn forget(state: &mut VfpState) {
+    unsafe {
+        if OWNER == state as *mut VfpState {
+            OWNER = null_mut();
+        }
+    }
+}
+
+/// Called when an interrupt is taken, before its handlers run.
+pub(crate) unsafe fn irq_enter() {
+    unsafe {
+        INTERRUPTED = CURRENT;
+        CURRENT = &raw mut IRQ;
+        enable_for_current();
+    }
+}
+
+/// Called when the interrupt handlers are done, before going back to the interrupted context.
+pub(crate) unsafe fn irq_exit() {
+    unsafe {
+        // What the handlers left in the registers is of no use to anybody.
+        if OWNER == &raw mut IRQ {
+            OWNER = null_mut();
+        }
+        CURRENT = INTERRUPTED;
+        enable_for_current();
+    }
+}
+
+/// Called for an undefined instruction at `pc`: true if it was a VFP instruction run while
+/// the VFP was off for the context, which can now run it again.
+pub(crate) unsafe fn trap(pc: u32) -> bool {
+    // CDP, MRC and MCR, or LDC and STC, on coprocessor 10 or 11.
+    let instruction = unsafe { with_exposed_provenance::<u32>(pc as usize).read_volatile() };
+    let coprocessor = instruction & (0xe << 8) == 0xa << 8;
+    let is_vfp = coprocessor
+        && (instruction & (0xf << 24) == 0xe << 24 || instruction & (0x7 << 25) == 0x6 << 25);
+    let fpexc = fpexc_get();
+    if !is_vfp || fpexc & FPEXC_EN != 0 {
+        return false;
+    }
+
+    fpexc_set(fpexc | FPEXC_EN);
+    unsafe {
+        if !OWNER.is_null() {
+            vfp_save(OWNER);
+        }
+        vfp_restore(CURRENT);
+        OWNER = CURRENT;
+    }
+    true
+}
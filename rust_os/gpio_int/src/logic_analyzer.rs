@@ -11,10 +11,10 @@ use core::time::Duration;
 use crab_pi::cache::caches_enable;
 use crab_pi::cycle_count::cycle_cnt_read;
 use crab_pi::gpio::{
-    GPIO_FUNC, GPIOEvent, gpio_int_falling_edge, gpio_int_rising_edge, gpio_interrupt_enable,
-    gpio_interrupt_init, gpio_register_interrupt_handler, gpio_set_function, gpio_write,
+    GPIO_FUNC, GPIOEvent, gpio_fiq_init, gpio_int_falling_edge, gpio_int_rising_edge,
+    gpio_register_interrupt_handler, gpio_set_function, gpio_write,
 };
-use crab_pi::interrupt::{enable_interrupts, interrupt_init};
+use crab_pi::interrupt::{enable_fiq, interrupt_init};
 use crab_pi::memory::dev_barrier;
 use crab_pi::println;
 use crab_pi::timer::sleep;
@@ -83,16 +83,15 @@ fn __user_main() {
 
     println!("Cycles per bit: {}", uart.get_cycles_per_bit());
 
-    // Initialize Interrupts
+    // Initialize Interrupts: edges come as the FIQ, so nothing delays their timestamps.
     unsafe {
         interrupt_init();
         caches_enable();
 
         gpio_register_interrupt_handler(IN_PIN, gpio_handler);
-        gpio_interrupt_init();
-        gpio_interrupt_enable();
+        gpio_fiq_init();
 
-        enable_interrupts();
+        enable_fiq();
     }
 
     for _ in 0..2 {
@@ -1,16 +1,55 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![cfg_attr(test, feature(thread_local))]
+#![test_runner(crab_pi::testing::run_tests)]
+#![reexport_test_harness_main = "test_main"]
 extern crate alloc;
 
+#[cfg(test)]
+mod t10_spawn;
+#[cfg(test)]
+mod t11_stack;
+#[cfg(test)]
+mod t12_sync;
+#[cfg(test)]
+mod t13_sleep;
+#[cfg(test)]
+mod t14_realtime;
+#[cfg(test)]
+mod t15_vfp;
+#[cfg(test)]
+mod t16_async;
+#[cfg(test)]
+mod t17_threads;
+#[cfg(test)]
+mod t18_tls;
+#[cfg(test)]
+mod t19_fiq;
+#[cfg(test)]
+mod t20_fault;
+#[cfg(test)]
+mod t21_reset;
+#[cfg(test)]
+mod t22_timeout;
+#[cfg(test)]
 mod t3_test_exit;
+#[cfg(test)]
 mod t4_test_yield;
+#[cfg(test)]
 mod t5_test_implicit_exit;
+#[cfg(test)]
 mod t7_realtime_yield;
+#[cfg(test)]
+mod t8_preempt;
+#[cfg(test)]
+mod t9_join;
 
 #[unsafe(no_mangle)]
 fn __user_main() {
-    // t3_test_exit::t3_test_exit();
-    // t4_test_yield::t4_test_yield();
-    // t5_test_implicit_exit::t5_test_implicit_exit();
-    t7_realtime_yield::t7_realtime_yield();
+    #[cfg(test)]
+    test_main();
+
+    #[cfg(not(test))]
+    crab_pi::println!("the thread tests run with `cargo test -p thread`");
 }
@@ -0,0 +1,36 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crab_pi::println;
+use crab_pi::thread::{rpi_yield, spawn, spawn_named};
+
+#[test_case]
+fn t10_spawn() {
+    let counter = Arc::new(AtomicU32::new(0));
+    let handles = (1..=4)
+        .map(|n| {
+            let counter = counter.clone();
+            spawn(move || {
+                for _ in 0..n {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    rpi_yield();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Moved into the thread and dropped there.
+    let words = vec!["moved", "into", "a", "thread"];
+    let named = spawn_named("words", move || {
+        assert_eq!(words.concat(), "movedintoathread");
+    });
+
+    for handle in handles {
+        assert_eq!(handle.join(), 0);
+    }
+    assert_eq!(named.join(), 0);
+    assert_eq!(counter.load(Ordering::Relaxed), 1 + 2 + 3 + 4);
+    assert_eq!(Arc::strong_count(&counter), 1);
+    println!("SUCCESS");
+}
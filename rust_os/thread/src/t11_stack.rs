@@ -0,0 +1,69 @@
+use core::hint::black_box;
+use crab_pi::println;
+use crab_pi::thread::{
+    ThreadState, rpi_stack_high_water, rpi_threads, rpi_yield, spawn_with_stack,
+};
+
+// Uses about `depth * 64` bytes of stack.
+fn recurse(depth: u32) -> u32 {
+    let words = black_box([depth; 16]);
+    if depth == 0 {
+        rpi_yield();
+        return 0;
+    }
+    words[depth as usize % 16] + recurse(depth - 1)
+}
+
+#[test_case]
+fn t11_stack() {
+    let shallow = spawn_with_stack("shallow", 1024, || {
+        black_box(recurse(1));
+    });
+    let deep = spawn_with_stack("deep", 16 * 1024, || {
+        black_box(recurse(100));
+    });
+
+    // Not yet run: nothing used.
+    assert_eq!(deep.stack_high_water(), Some(0));
+
+    // Both have yielded at the bottom of their recursion, and exited: the mark stays.
+    let deep_id = deep.thread_id();
+    let shallow_id = shallow.thread_id();
+    assert_eq!(deep.join(), 0);
+    let deep_used = rpi_stack_high_water(deep_id).unwrap();
+    let shallow_used = rpi_stack_high_water(shallow_id).unwrap();
+    println!("stack used: shallow {}, deep {}", shallow_used, deep_used);
+    assert!(shallow_used > 0 && shallow_used <= 1024);
+    assert!(deep_used > 100 * 64 && deep_used <= 16 * 1024);
+    assert_eq!(shallow.join(), 0);
+    println!("SUCCESS");
+}
+
+fn exited() -> usize {
+    rpi_threads()
+        .iter()
+        .filter(|thread| thread.state == ThreadState::Exited)
+        .count()
+}
+
+#[test_case]
+fn t11_stack_reuse() {
+    // Each fork wants a bigger stack than any exited thread has: one of them goes.
+    let before = exited().max(1);
+    for i in 1..=8 {
+        let thread = spawn_with_stack("growing", i * 1024, move || {
+            black_box(recurse(i as u32 * 8));
+        });
+        assert_eq!(thread.join(), 0);
+        assert!(exited() <= before, "{} exited threads kept", exited());
+    }
+
+    // A smaller one makes do with a bigger stack: nothing new is kept.
+    let kept = exited();
+    let thread = spawn_with_stack("small", 1024, || {
+        black_box(recurse(1));
+    });
+    assert_eq!(thread.join(), 0);
+    assert_eq!(exited(), kept);
+    println!("SUCCESS");
+}
@@ -0,0 +1,131 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crab_pi::println;
+use crab_pi::thread::{Condvar, Mutex, Semaphore, channel, rpi_yield, spawn};
+
+static COUNT: Mutex<u32> = Mutex::new(0);
+static PINGS: Semaphore = Semaphore::new(0);
+static PONGS: Semaphore = Semaphore::new(0);
+
+#[test_case]
+fn t12_mutex() {
+    // Each yields while holding the lock: the others block instead of getting in.
+    let threads = (0..4)
+        .map(|_| {
+            spawn(|| {
+                for _ in 0..10 {
+                    let mut count = COUNT.lock();
+                    let before = *count;
+                    rpi_yield();
+                    *count = before + 1;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        assert_eq!(thread.join(), 0);
+    }
+    assert_eq!(*COUNT.lock(), 40);
+    assert!(COUNT.try_lock().is_some());
+    println!("SUCCESS");
+}
+
+#[test_case]
+fn t12_semaphore() {
+    let ponger = spawn(|| {
+        for _ in 0..5 {
+            PINGS.wait();
+            PONGS.post();
+        }
+    });
+    for _ in 0..5 {
+        PINGS.post();
+        // Outside a thread: runs the ponger until it posts.
+        PONGS.wait();
+    }
+    assert_eq!(ponger.join(), 0);
+    assert_eq!(PINGS.count(), 0);
+    assert!(!PONGS.try_wait());
+    println!("SUCCESS");
+}
+
+#[test_case]
+fn t12_condvar() {
+    let ready = Arc::new((Mutex::new(0), Condvar::new()));
+    let waiters = (1..=3)
+        .map(|n| {
+            let ready = ready.clone();
+            spawn(move || {
+                let (level, changed) = &*ready;
+                let mut level = level.lock();
+                while *level < n {
+                    level = changed.wait(level);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let (level, changed) = &*ready;
+    let raiser = {
+        let ready = ready.clone();
+        spawn(move || {
+            let (level, changed) = &*ready;
+            for _ in 0..3 {
+                *level.lock() += 1;
+                changed.notify_all();
+                rpi_yield();
+            }
+        })
+    };
+    for waiter in waiters {
+        assert_eq!(waiter.join(), 0);
+    }
+    assert_eq!(raiser.join(), 0);
+    assert_eq!(*level.lock(), 3);
+    changed.notify_one();
+    println!("SUCCESS");
+}
+
+#[test_case]
+fn t12_channel() {
+    // Two senders and two receivers through room for two: both sides block in turn.
+    let (sender, receiver) = channel::<u32>(2);
+    let senders = (0..2)
+        .map(|first| {
+            let sender = sender.clone();
+            spawn(move || {
+                for value in (first..100).step_by(2) {
+                    sender.send(value).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    let total = Arc::new(Mutex::new(0));
+    let receivers = (0..2)
+        .map(|_| {
+            let receiver = receiver.clone();
+            let total = total.clone();
+            spawn(move || {
+                // Ends when both senders are gone.
+                while let Some(value) = receiver.recv() {
+                    *total.lock() += value;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in senders.into_iter().chain(receivers) {
+        assert_eq!(thread.join(), 0);
+    }
+    assert_eq!(*total.lock(), (0..100).sum::<u32>());
+    assert_eq!(receiver.try_recv(), None);
+
+    let (sender, receiver) = channel(1);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(2));
+    drop(receiver);
+    assert_eq!(sender.send(3), Err(3));
+    println!("SUCCESS");
+}
@@ -0,0 +1,71 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::null;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use crab_pi::exit::{exit_code, set_exit_code};
+use crab_pi::println;
+use crab_pi::thread::{
+    Mutex, rpi_exit, rpi_fork, rpi_thread_start, rpi_yield, spawn, thread_sleep,
+};
+use crab_pi::timer::timer_get_usec;
+
+#[test_case]
+fn t13_sleep() {
+    let woken = Arc::new(Mutex::new(Vec::new()));
+    let spins = Arc::new(AtomicU32::new(0));
+    let start = timer_get_usec();
+
+    // Started longest first: they wake in order of their deadlines.
+    let sleepers = [30, 10, 20]
+        .into_iter()
+        .map(|msec| {
+            let woken = woken.clone();
+            spawn(move || {
+                thread_sleep(Duration::from_millis(msec));
+                let slept = timer_get_usec().wrapping_sub(start);
+                assert!(slept >= msec as u32 * 1000, "slept {} usec", slept);
+                woken.lock().push(msec);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Sleepers leave it the CPU to itself.
+    let spinner = {
+        let spins = spins.clone();
+        spawn(move || {
+            while timer_get_usec().wrapping_sub(start) < 5_000 {
+                spins.fetch_add(1, Ordering::Relaxed);
+                rpi_yield();
+            }
+        })
+    };
+
+    for thread in sleepers.into_iter().chain([spinner]) {
+        assert_eq!(thread.join(), 0);
+    }
+    assert_eq!(*woken.lock(), [10, 20, 30]);
+    assert!(spins.load(Ordering::Relaxed) > 0);
+    println!("SUCCESS");
+}
+
+extern "C" fn early(_arg: *const u32) {
+    rpi_exit(7);
+}
+
+extern "C" fn sleepy(_arg: *const u32) {
+    thread_sleep(Duration::from_millis(5));
+    rpi_exit(3);
+}
+
+#[test_case]
+fn t13_sleeper_exits_last() {
+    // `early` is the last one in `RUN_Q` when it exits, but not the last to run.
+    rpi_fork(sleepy, null());
+    rpi_fork(early, null());
+    rpi_thread_start();
+
+    assert_eq!(exit_code(), 3);
+    set_exit_code(0);
+    println!("SUCCESS");
+}
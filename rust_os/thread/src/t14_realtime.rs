@@ -0,0 +1,109 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use crab_pi::println;
+use crab_pi::thread::{
+    Mutex, SchedClass, rpi_deadline_stats, rpi_preempt_disable, rpi_preempt_enable, rpi_yield,
+    spawn, spawn_periodic,
+};
+use crab_pi::timer::timer_get_usec;
+
+#[test_case]
+fn t14_priority() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let threads = [
+        SchedClass::Normal,
+        SchedClass::Priority(1),
+        SchedClass::Priority(3),
+        SchedClass::Priority(2),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, class)| {
+        let order = order.clone();
+        let thread = spawn(move || {
+            // Outranked by nobody still ready: yielding gives the CPU back to ourselves.
+            rpi_yield();
+            order.lock().push(i);
+        });
+        assert!(thread.set_class(class));
+        thread
+    })
+    .collect::<Vec<_>>();
+
+    for thread in threads {
+        assert_eq!(thread.join(), 0);
+    }
+    assert_eq!(*order.lock(), [2, 3, 1, 0]);
+    println!("SUCCESS");
+}
+
+fn busy(usec: u32) {
+    let start = timer_get_usec();
+    while timer_get_usec().wrapping_sub(start) < usec {}
+}
+
+#[test_case]
+fn t14_deadlines() {
+    rpi_preempt_enable(Duration::from_millis(1));
+
+    // Two control loops with room to spare, next to a background thread that never yields:
+    // they preempt it when they are released.
+    let mut fast_jobs = 0;
+    let fast = spawn_periodic(
+        "fast",
+        Duration::from_millis(2),
+        Duration::from_micros(500),
+        move || {
+            busy(200);
+            fast_jobs += 1;
+            fast_jobs < 20
+        },
+    );
+    let mut slow_jobs = 0;
+    let slow = spawn_periodic(
+        "slow",
+        Duration::from_millis(5),
+        Duration::from_millis(2),
+        move || {
+            busy(1000);
+            slow_jobs += 1;
+            slow_jobs < 8
+        },
+    );
+    let background = spawn(|| busy(50_000));
+
+    let fast_id = fast.thread_id();
+    let slow_id = slow.thread_id();
+    for thread in [fast, slow, background] {
+        assert_eq!(thread.join(), 0);
+    }
+
+    // One that cannot keep up: each job takes longer than its period. Alone, since overload
+    // would make the others miss too.
+    let mut late_jobs = 0;
+    let late = spawn_periodic(
+        "late",
+        Duration::from_millis(1),
+        Duration::from_micros(500),
+        move || {
+            busy(1500);
+            late_jobs += 1;
+            late_jobs < 3
+        },
+    );
+
+    let late_id = late.thread_id();
+    assert_eq!(late.join(), 0);
+    rpi_preempt_disable();
+
+    let fast = rpi_deadline_stats(fast_id).unwrap();
+    let slow = rpi_deadline_stats(slow_id).unwrap();
+    let late = rpi_deadline_stats(late_id).unwrap();
+    println!("fast {:?}\nslow {:?}\nlate {:?}", fast, slow, late);
+    assert_eq!((fast.jobs, fast.misses, fast.overruns), (20, 0, 0));
+    assert_eq!((slow.jobs, slow.misses), (8, 0));
+    assert_eq!((late.jobs, late.overruns), (3, 3));
+    assert!(late.misses > 0);
+    println!("SUCCESS");
+}
@@ -0,0 +1,62 @@
+use alloc::vec::Vec;
+use core::hint::black_box;
+use core::time::Duration;
+use crab_pi::println;
+use crab_pi::thread::{rpi_preempt_disable, rpi_preempt_enable, rpi_yield, spawn};
+
+// Keeps `x` in VFP registers across `between`, which runs the other threads.
+fn series(seed: u32, steps: u32, between: fn()) -> f64 {
+    let mut x = black_box(seed as f64 + 0.25);
+    let mut y = black_box(1.0f32 / (seed as f32 + 3.0));
+    for _ in 0..steps {
+        x = x * 1.000_1 + y as f64;
+        y = y * 0.5 + 0.125;
+        between();
+    }
+    x + y as f64
+}
+
+fn nothing() {}
+
+fn spin() {
+    black_box(0);
+}
+
+fn run_all(between: fn(), steps: u32) {
+    let expected = (1..=4)
+        .map(|seed| series(seed, steps, nothing))
+        .collect::<Vec<_>>();
+    let threads = (1..=4)
+        .map(|seed| {
+            let expected = expected[seed as usize - 1];
+            spawn(move || {
+                // Exact: every thread computes the same as the kernel did.
+                let x = series(seed, steps, between);
+                assert!(
+                    x.to_bits() == expected.to_bits(),
+                    "thread {} got {}",
+                    seed,
+                    x
+                );
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        assert_eq!(thread.join(), 0);
+    }
+}
+
+#[test_case]
+fn t15_vfp_yield() {
+    run_all(rpi_yield, 100);
+    println!("SUCCESS");
+}
+
+#[test_case]
+fn t15_vfp_preempt() {
+    rpi_preempt_enable(Duration::from_micros(200));
+    run_all(spin, 2_000);
+    rpi_preempt_disable();
+    println!("SUCCESS");
+}
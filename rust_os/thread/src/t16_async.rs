@@ -0,0 +1,46 @@
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::time::Duration;
+use crab_pi::executor::{Executor, block_on, sleep, sleep_until};
+use crab_pi::println;
+use crab_pi::timer::timer_get_usec;
+
+#[test_case]
+fn t16_async_sleep() {
+    let woken = Rc::new(RefCell::new(Vec::new()));
+    let start = timer_get_usec();
+
+    // Spawned longest first: the alarm wakes them in order of their deadlines.
+    let mut executor = Executor::new();
+    for msec in [30, 10, 20] {
+        let woken = woken.clone();
+        executor.spawn(async move {
+            sleep(Duration::from_millis(msec)).await;
+            woken.borrow_mut().push(msec);
+        });
+    }
+    executor.run();
+
+    let elapsed = timer_get_usec().wrapping_sub(start);
+    println!("woken {:?} after {}usec", woken.borrow(), elapsed);
+    assert_eq!(*woken.borrow(), [10, 20, 30]);
+    assert!(elapsed >= 30_000);
+    println!("SUCCESS");
+}
+
+#[test_case]
+fn t16_async_block_on() {
+    let start = timer_get_usec();
+    let elapsed = block_on(async {
+        // Already due: ready on the first poll.
+        sleep(Duration::ZERO).await;
+        sleep(Duration::from_millis(5)).await;
+        sleep_until(start.wrapping_add(15_000)).await;
+        timer_get_usec().wrapping_sub(start)
+    });
+
+    println!("block_on took {}usec", elapsed);
+    assert!(elapsed >= 15_000);
+    println!("SUCCESS");
+}
@@ -0,0 +1,63 @@
+use alloc::sync::Arc;
+use core::time::Duration;
+use crab_pi::println;
+use crab_pi::thread::{
+    Semaphore, ThreadInfo, ThreadState, rpi_cur_thread_id, rpi_print_threads, rpi_thread_start,
+    rpi_threads, rpi_yield, spawn_named, thread_sleep,
+};
+
+fn info(thread_id: usize) -> ThreadInfo {
+    rpi_threads()
+        .into_iter()
+        .find(|thread| thread.thread_id == thread_id)
+        .unwrap()
+}
+
+#[test_case]
+fn t17_threads() {
+    let sem = Arc::new(Semaphore::new(0));
+    let waiter = spawn_named("waiter", {
+        let sem = sem.clone();
+        move || sem.wait()
+    });
+    let sleeper = spawn_named("sleeper", || thread_sleep(Duration::from_millis(20)));
+    let quitter = spawn_named("quitter", || {});
+    let ready = spawn_named("ready", || {
+        rpi_yield();
+        rpi_yield();
+    });
+    let ids = [
+        waiter.thread_id(),
+        sleeper.thread_id(),
+        quitter.thread_id(),
+        ready.thread_id(),
+    ];
+
+    // Runs after each of the others has run once.
+    let lister = spawn_named("lister", move || {
+        rpi_print_threads();
+        let me = info(rpi_cur_thread_id());
+        assert_eq!(
+            (me.name.as_str(), me.state),
+            ("lister", ThreadState::Running)
+        );
+        assert_eq!(info(ids[0]).state, ThreadState::Blocked);
+        assert_eq!(info(ids[1]).state, ThreadState::Blocked);
+        assert_eq!(info(ids[2]).state, ThreadState::Exited);
+        assert_eq!(info(ids[3]).state, ThreadState::Ready);
+        assert!(me.cycles > 0);
+        sem.post();
+    });
+    let lister = lister.thread_id();
+    rpi_thread_start();
+
+    rpi_print_threads();
+    for id in ids.into_iter().chain([lister]) {
+        assert_eq!(info(id).state, ThreadState::Exited);
+    }
+    // Blocked once, so switched to twice; the yielder three times.
+    assert_eq!(info(ids[0]).switches, 2);
+    assert_eq!(info(ids[2]).switches, 1);
+    assert_eq!(info(ids[3]).switches, 3);
+    println!("SUCCESS");
+}
@@ -0,0 +1,55 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cell::Cell;
+use crab_pi::println;
+use crab_pi::thread::{rpi_cur_thread_id, rpi_yield, spawn};
+
+// In `.tdata`, and in `.tbss`.
+#[thread_local]
+static COUNT: Cell<u32> = Cell::new(100);
+#[thread_local]
+static SEEN: Cell<[u32; 4]> = Cell::new([0; 4]);
+
+fn user_rw_get() -> u32 {
+    let user_rw: u32;
+    unsafe { asm!("mrc p15, 0, {}, c13, c0, 2", out(reg) user_rw) };
+    user_rw
+}
+
+fn user_rw_set(user_rw: u32) {
+    unsafe { asm!("mcr p15, 0, {}, c13, c0, 2", in(reg) user_rw) };
+}
+
+#[test_case]
+fn t18_tls() {
+    COUNT.set(7);
+
+    let threads: Vec<_> = (1..=3)
+        .map(|step| {
+            spawn(move || {
+                assert_eq!(COUNT.get(), 100);
+                assert_eq!(SEEN.get(), [0; 4]);
+                let id = rpi_cur_thread_id() as u32;
+                user_rw_set(id);
+                for i in 0..4 {
+                    COUNT.set(COUNT.get() + step);
+                    let mut seen = SEEN.get();
+                    seen[i] = step;
+                    SEEN.set(seen);
+                    rpi_yield();
+                }
+                assert_eq!(COUNT.get(), 100 + 4 * step);
+                assert_eq!(SEEN.get(), [step; 4]);
+                assert_eq!(user_rw_get(), id);
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join(), 0);
+    }
+
+    // A thread that reuses an exited one's block starts afresh.
+    assert_eq!(spawn(|| assert_eq!(COUNT.get(), 100)).join(), 0);
+    assert_eq!(COUNT.get(), 7);
+    println!("SUCCESS");
+}
@@ -0,0 +1,54 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use crab_pi::interrupt::{
+    FiqSource, disable_fiq, enable_fiq, interrupts_restore, interrupts_save, register_fiq_handler,
+    unregister_fiq_handler,
+};
+use crab_pi::println;
+use crab_pi::timer::{timer_alarm_clear, timer_alarm_set, timer_get_usec};
+
+// The system timer compare the FIQ comes from.
+const ALARM: usize = 1;
+
+static FIQS: AtomicU32 = AtomicU32::new(0);
+static LAST_PC: AtomicU32 = AtomicU32::new(0);
+
+fn alarm_fiq(pc: u32) {
+    unsafe { timer_alarm_clear(ALARM) };
+    LAST_PC.store(pc, Ordering::Relaxed);
+    FIQS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn t19_fiq() {
+    unsafe { register_fiq_handler(FiqSource::Irq1(ALARM), alarm_fiq) };
+    enable_fiq();
+
+    // With IRQs off only the FIQ gets through, so the timeout is kept here.
+    let cpsr = interrupts_save();
+    let mut late = None;
+    for expected in 1..=3 {
+        unsafe { timer_alarm_set(ALARM, timer_get_usec().wrapping_add(1000)) };
+        let start = timer_get_usec();
+        while FIQS.load(Ordering::Relaxed) < expected {
+            if timer_get_usec().wrapping_sub(start) > 100_000 {
+                late = Some(expected);
+                break;
+            }
+        }
+    }
+    interrupts_restore(cpsr);
+    disable_fiq();
+    unsafe {
+        unregister_fiq_handler();
+        timer_alarm_clear(ALARM);
+    }
+
+    println!(
+        "{} FIQs, the last at pc={:#x}",
+        FIQS.load(Ordering::Relaxed),
+        LAST_PC.load(Ordering::Relaxed)
+    );
+    assert_eq!(late, None, "no FIQ");
+    assert_eq!(FIQS.load(Ordering::Relaxed), 3);
+    println!("SUCCESS");
+}
@@ -0,0 +1,29 @@
+use crab_pi::fault::FaultKind;
+use crab_pi::println;
+
+// Status values as the DFSR/IFSR hold them: the write bit and the domain don't change the kind.
+#[test_case]
+fn t20_fault_kind() {
+    let cases = [
+        (0x001, FaultKind::Alignment),
+        (0x805, FaultKind::Translation { page: false }),
+        (0x0f7, FaultKind::Translation { page: true }),
+        (0x006, FaultKind::AccessFlag { page: true }),
+        (0x019, FaultKind::Domain { page: false }),
+        (0x80d, FaultKind::Permission { page: false }),
+        (0x02f, FaultKind::Permission { page: true }),
+        (0x008, FaultKind::ExternalAbort { imprecise: false }),
+        (0xc06, FaultKind::ExternalAbort { imprecise: true }),
+        (0x00c, FaultKind::TableWalk { page: false }),
+        (0x00e, FaultKind::TableWalk { page: true }),
+        (0x004, FaultKind::InstructionCacheMaintenance),
+        (0x002, FaultKind::Debug),
+        (0x000, FaultKind::Unknown(0)),
+        (0x40a, FaultKind::Unknown(0b11010)),
+    ];
+    for (status, kind) in cases {
+        println!("{:#05x}: {:?}", status, FaultKind::from_status(status));
+        assert_eq!(FaultKind::from_status(status), kind);
+    }
+    println!("SUCCESS");
+}
@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use crab_pi::println;
+use crab_pi::testing::catch;
+use crab_pi::thread::{
+    Semaphore, ThreadState, rpi_thread_reset, rpi_thread_start, rpi_threads, spawn,
+};
+
+static SEM: Semaphore = Semaphore::new(0);
+static STALE_RAN: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn t21_reset_blocked() {
+    // Abandoned like a failing test, with the waiter blocked.
+    spawn(|| {
+        SEM.wait();
+        STALE_RAN.store(true, Ordering::Relaxed);
+    });
+    assert!(!catch(|| {
+        spawn(|| panic!("abandoning the waiter"));
+        rpi_thread_start();
+    }));
+    rpi_thread_reset();
+    assert!(
+        rpi_threads()
+            .iter()
+            .all(|thread| thread.state == ThreadState::Exited)
+    );
+
+    // The post wakes no one, so a new thread takes it.
+    SEM.post();
+    let taker = spawn(|| SEM.wait());
+    rpi_thread_start();
+    assert_eq!(taker.join(), 0);
+    assert!(!STALE_RAN.load(Ordering::Relaxed));
+    assert!(
+        rpi_threads()
+            .iter()
+            .all(|thread| thread.state == ThreadState::Exited)
+    );
+    println!("SUCCESS");
+}
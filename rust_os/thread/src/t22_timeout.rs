@@ -0,0 +1,43 @@
+use core::hint::spin_loop;
+use core::time::Duration;
+use crab_pi::println;
+use crab_pi::testing::{DEFAULT_TIMEOUT, WithTimeout, catch};
+use crab_pi::timer::timer_get_usec;
+use crab_pi::with_timeout;
+
+fn spin_for(usec: u32) {
+    let start = timer_get_usec();
+    while timer_get_usec().wrapping_sub(start) < usec {
+        spin_loop();
+    }
+}
+
+fn t22_long() {
+    // Past the default timeout, but not its own.
+    spin_for(DEFAULT_TIMEOUT.as_micros() as u32 * 3 / 2);
+    println!("SUCCESS");
+}
+
+#[test_case]
+static T22_LONG: WithTimeout = with_timeout!(t22_long, Duration::from_secs(3));
+
+fn spin_forever() {
+    loop {
+        spin_loop();
+    }
+}
+
+#[test_case]
+fn t22_timeout_fires() {
+    let short = with_timeout!(spin_forever, Duration::from_millis(50));
+    let start = timer_get_usec();
+    assert!(!catch(short));
+    let waited = timer_get_usec().wrapping_sub(start);
+    println!("abandoned after {} usec", waited);
+    assert!(
+        waited >= 50_000 && waited < DEFAULT_TIMEOUT.as_micros() as u32,
+        "abandoned after {} usec",
+        waited
+    );
+    println!("SUCCESS");
+}
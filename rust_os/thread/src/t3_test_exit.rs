@@ -10,7 +10,8 @@ extern "C" fn trivial(arg: *const u32) {
     rpi_exit(0);
 }
 
-pub fn t3_test_exit() {
+#[test_case]
+fn t3_test_exit() {
     for i in 0..10 {
         let arg = Box::new(i);
         rpi_fork(trivial, Box::into_raw(arg) as *const u32);
@@ -9,7 +9,8 @@ extern "C" fn trivial(arg: *const u32) {
     rpi_exit(0);
 }
 
-pub fn t4_test_yield() {
+#[test_case]
+fn t4_test_yield() {
     for i in 0..10 {
         let arg = Box::new(i);
         rpi_fork(trivial, Box::into_raw(arg) as *const u32);
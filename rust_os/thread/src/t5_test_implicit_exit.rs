@@ -5,28 +5,37 @@ use crab_pi::thread::{rpi_cur_thread_id, rpi_fork, rpi_thread_start};
 
 static mut thread_count: usize = 0;
 static mut thread_sum: usize = 0;
+// Thread ids keep counting across tests: the id of the first thread the test forks.
+static mut first_tid: usize = 0;
 
 extern "C" fn thread_code(arg: *const u32) {
     let x = unsafe { *arg };
 
     println!("in thread tid = {} with x = {}", rpi_cur_thread_id(), x);
 
-    assert_eq!(rpi_cur_thread_id() as u32, x + 1);
-
     unsafe {
+        assert_eq!(rpi_cur_thread_id(), first_tid + x as usize);
+
         thread_count += 1;
         thread_sum += x as usize;
     }
 }
 
-pub fn t5_test_implicit_exit() {
+#[test_case]
+fn t5_test_implicit_exit() {
     let n = 30;
 
     let mut sum = 0;
     for i in 0..n {
         let x = Box::new(i);
         sum += i;
-        rpi_fork(thread_code, Box::into_raw(x) as *const u32);
+        let tid = rpi_fork(thread_code, Box::into_raw(x) as *const u32).thread_id();
+        unsafe {
+            if i == 0 {
+                first_tid = tid;
+            }
+            assert_eq!(tid, first_tid + i as usize);
+        }
     }
     rpi_thread_start();
 
@@ -34,10 +34,10 @@ void blink(void *arg) {
 
     for(int i = 0; i < 1000; i++) {
         gpio_set_on(p->pin);
-        wait_usec(on_usec);
+        thread_sleep(Duration::from_micros(on_usec as u64));
 
         gpio_set_off(p->pin);
-        wait_usec(off_usec);
+        thread_sleep(Duration::from_micros(off_usec as u64));
     }
 }
 
@@ -55,32 +55,17 @@ void notmain(void) {
 }
 
  */
-use alloc::boxed::Box;
+use core::time::Duration;
 use crab_pi::gpio::{GPIO_FUNC, gpio_set_function, gpio_set_off, gpio_set_on};
 use crab_pi::println;
-use crab_pi::thread::{rpi_fork, rpi_thread_start, rpi_yield};
-use crab_pi::timer::timer_get_usec;
+use crab_pi::thread::{rpi_thread_start, spawn_named, thread_sleep};
 
 struct pwm {
     duty: u32,
     pin: u32,
 }
 
-fn wait_usec(n: u32) {
-    // println!("wait_usec: {}", n);
-    let start = unsafe { timer_get_usec() };
-
-    loop {
-        if unsafe { timer_get_usec() } - start >= n {
-            return;
-        }
-        rpi_yield();
-    }
-}
-
-extern "C" fn blink(arg: *const u32) {
-    let duty = unsafe { Box::from_raw(arg as *mut pwm) };
-
+fn blink(duty: pwm) {
     gpio_set_function(duty.pin, GPIO_FUNC::OUTPUT);
     assert!(duty.duty > 0 && duty.duty <= 100);
 
@@ -89,19 +74,20 @@ extern "C" fn blink(arg: *const u32) {
 
     for i in 0..1000 {
         gpio_set_on(duty.pin);
-        wait_usec(on_usec);
+        thread_sleep(Duration::from_micros(on_usec as u64));
 
         gpio_set_off(duty.pin);
-        wait_usec(off_usec);
+        thread_sleep(Duration::from_micros(off_usec as u64));
     }
 }
 
-pub fn t7_realtime_yield() {
-    let t75 = Box::new(pwm { duty: 75, pin: 20 });
-    let t25 = Box::new(pwm { duty: 25, pin: 21 });
+#[test_case]
+fn t7_realtime_yield() {
+    let t75 = pwm { duty: 75, pin: 20 };
+    let t25 = pwm { duty: 25, pin: 21 };
 
-    rpi_fork(blink, Box::into_raw(t75) as *const u32);
-    rpi_fork(blink, Box::into_raw(t25) as *const u32);
+    spawn_named("blink 20", move || blink(t75));
+    spawn_named("blink 21", move || blink(t25));
 
     rpi_thread_start();
     println!("SUCCESS")
@@ -0,0 +1,29 @@
+use core::ptr::null;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use crab_pi::println;
+use crab_pi::thread::{rpi_fork, rpi_preempt_disable, rpi_preempt_enable, rpi_thread_start};
+
+static SECOND_RAN: AtomicBool = AtomicBool::new(false);
+
+// Never yields: it only gets past the loop if it is preempted so `second` can run.
+extern "C" fn first(_arg: *const u32) {
+    while !SECOND_RAN.load(Ordering::Relaxed) {}
+    println!("first saw second run");
+}
+
+extern "C" fn second(_arg: *const u32) {
+    SECOND_RAN.store(true, Ordering::Relaxed);
+}
+
+#[test_case]
+fn t8_preempt() {
+    rpi_preempt_enable(Duration::from_millis(1));
+    rpi_fork(first, null());
+    rpi_fork(second, null());
+    rpi_thread_start();
+    rpi_preempt_disable();
+
+    assert!(SECOND_RAN.load(Ordering::Relaxed));
+    println!("SUCCESS");
+}
@@ -0,0 +1,42 @@
+use alloc::vec::Vec;
+use core::ptr::null;
+use crab_pi::kmalloc::kmalloc_heap_ptr;
+use crab_pi::println;
+use crab_pi::thread::{rpi_exit, rpi_fork, rpi_thread_start};
+
+extern "C" fn exit_with_arg(arg: *const u32) {
+    rpi_exit(arg as i32);
+}
+
+extern "C" fn returns(_arg: *const u32) {}
+
+// Joins its own children: the joining thread yields until they are done.
+extern "C" fn parent(_arg: *const u32) {
+    let children = (10..13)
+        .map(|code| rpi_fork(exit_with_arg, code as *const u32))
+        .collect::<Vec<_>>();
+    let sum = children.into_iter().map(|child| child.join()).sum::<i32>();
+    rpi_exit(sum);
+}
+
+#[test_case]
+fn t9_join() {
+    let first = rpi_fork(exit_with_arg, 7 as *const u32);
+    let second = rpi_fork(returns, null());
+    let parent = rpi_fork(parent, null());
+
+    // Not in a thread: this runs them all.
+    assert_eq!(parent.join(), 10 + 11 + 12);
+    assert_eq!(second.join(), 0);
+    assert_eq!(first.join(), 7);
+
+    // Exited threads are reused: forking more does not take another 8KB stack each time.
+    let heap_before = unsafe { kmalloc_heap_ptr() } as usize;
+    for _ in 0..20 {
+        rpi_fork(returns, null());
+        rpi_thread_start();
+    }
+    let grown = unsafe { kmalloc_heap_ptr() } as usize - heap_before;
+    assert!(grown < 8 * 1024, "heap grew by {} bytes", grown);
+    println!("SUCCESS");
+}
@@ -7,14 +7,14 @@ nice_path() {
 elf_path=$1
 target_dir=$(nice_path "$(dirname "$elf_path")")
 base_name=$(basename "$elf_path")
-bin_path="${target_dir}/${base_name/.elf/}.bin"
 list_path="${target_dir}/${base_name/.elf/}.list"
 
-# load_addr=0x8000
-
-arm-none-eabi-objcopy "$elf_path" -O binary "$bin_path"
-arm-none-eabi-objdump -D "$elf_path" > "$list_path"
-echo "Created $bin_path from $elf_path"
-# RUST_LOG=info okdude -l $load_addr "$bin_path"
+# pi-install reads the ELF directly; the listing is only generated when binutils is around.
+if command -v arm-none-eabi-objdump > /dev/null; then
+  arm-none-eabi-objdump -D "$elf_path" > "$list_path"
+  echo "Created $list_path from $elf_path"
+fi
 cd ../pi-install
-cargo run -p pi-install -- "../rust_os/$bin_path"
+# anything after the kernel goes to pi-install, e.g. `cargo test -p thread -- --junit $PWD/junit.xml`
+# (relative paths are taken from pi-install/).
+cargo run -p pi-install -- "../rust_os/$elf_path" "${@:2}"
@@ -0,0 +1,556 @@
+//! Framing for the `BOOT_OP` exchange between `pi-install` and `pi-bootloader`.
+//!
+//! Every frame starts with a little endian `BOOT_OP` word followed by a fixed number of
+//! little endian `u32` fields. Some frames are followed by a payload: its length is either in
+//! the header (`PUT_CHUNK`, `PRINT_STRING`, `TEST_START`, `PROFILE_SAMPLES`) or known from
+//! context (`PUT_CODE` carries the `nbytes` announced in `PUT_PROG_INFO`). Payloads are never
+//! buffered here, so the pi can read code straight into place.
+#![cfg_attr(not(test), no_std)]
+
+pub use constants::{BOOT_ERR, BOOT_OP, TEST_STATUS};
+
+/// Longest header: `PUT_CHUNK`, `PUT_PROG_INFO` and the test frames are an op plus three fields.
+pub const MAX_HEADER_LEN: usize = 4 * 4;
+
+/// A frame header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Frame {
+    /// pi: ready for a program.
+    GetProgInfo,
+    /// unix: where the program goes, how big it is and its crc32.
+    PutProgInfo { addr: u32, nbytes: u32, crc: u32 },
+    /// pi: send the whole program in one go; echoes the crc32.
+    GetCode { crc: u32 },
+    /// unix: followed by the `nbytes` of code from [`Frame::PutProgInfo`].
+    PutCode,
+    /// pi: send the program in acknowledged chunks of `chunk_size` bytes.
+    GetCodeChunked { crc: u32, chunk_size: u32 },
+    /// unix: followed by `nbytes` of code for chunk `index`.
+    PutChunk { index: u32, nbytes: u32, crc: u32 },
+    /// pi: chunk `index` arrived with crc32 `crc`.
+    ChunkAck { index: u32, crc: u32 },
+    /// pi: resend starting from chunk `index`.
+    ChunkNak { index: u32 },
+    /// pi: the program is in place and about to run.
+    BootSuccess,
+    /// pi: the boot failed for reason `code` (a `BOOT_ERR`); `detail` depends on the reason.
+    BootError { code: u32, detail: u32 },
+    /// pi: followed by `len` bytes of text to print.
+    PrintString { len: u32 },
+    /// kernel: done, with exit code `code` (an `i32`); the pi reboots next.
+    KernelExit { code: u32 },
+    /// kernel: test `index` of `total` starts; followed by its `len` byte name.
+    TestStart { index: u32, total: u32, len: u32 },
+    /// kernel: test `index` ended with `status` (a `TEST_STATUS`) after `usec` microseconds.
+    TestResult { index: u32, status: u32, usec: u32 },
+    /// kernel: followed by `count` samples, each a `u32` address and how many times the PC was
+    /// seen there, taken every `period_usec` microseconds.
+    ProfileSamples { period_usec: u32, count: u32 },
+}
+
+/// Bytes per sample in a `PROFILE_SAMPLES` payload.
+pub const PROFILE_SAMPLE_LEN: usize = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The input ends before the header does; `needed` is the full header length.
+    Truncated { needed: usize },
+    /// The first word is not a `BOOT_OP`.
+    UnknownOp(u32),
+    /// A known op that never starts a frame in this protocol (e.g. `BOOT_START`).
+    UnexpectedOp(BOOT_OP),
+    /// The header announces a payload longer than the decoder accepts.
+    PayloadTooLarge { len: u32, max: u32 },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EncodeError {
+    /// The payload does not match the length announced in the header.
+    PayloadLength { expected: usize, actual: usize },
+}
+
+fn field(bytes: &[u8], i: usize) -> u32 {
+    let start = 4 + i * 4;
+    u32::from_le_bytes([
+        bytes[start],
+        bytes[start + 1],
+        bytes[start + 2],
+        bytes[start + 3],
+    ])
+}
+
+/// Number of `u32` fields after the op word, or `None` if `op` never starts a frame.
+const fn field_count(op: BOOT_OP) -> Option<usize> {
+    match op {
+        BOOT_OP::GET_PROG_INFO | BOOT_OP::PUT_CODE => Some(0),
+        BOOT_OP::BOOT_SUCCESS => Some(0),
+        BOOT_OP::GET_CODE | BOOT_OP::CHUNK_NAK | BOOT_OP::PRINT_STRING => Some(1),
+        BOOT_OP::KERNEL_EXIT => Some(1),
+        BOOT_OP::GET_CODE_CHUNKED | BOOT_OP::CHUNK_ACK | BOOT_OP::BOOT_ERROR => Some(2),
+        BOOT_OP::PROFILE_SAMPLES => Some(2),
+        BOOT_OP::PUT_PROG_INFO | BOOT_OP::PUT_CHUNK => Some(3),
+        BOOT_OP::TEST_START | BOOT_OP::TEST_RESULT => Some(3),
+        BOOT_OP::BOOT_START => None,
+    }
+}
+
+impl Frame {
+    pub const fn op(&self) -> BOOT_OP {
+        match self {
+            Frame::GetProgInfo => BOOT_OP::GET_PROG_INFO,
+            Frame::PutProgInfo { .. } => BOOT_OP::PUT_PROG_INFO,
+            Frame::GetCode { .. } => BOOT_OP::GET_CODE,
+            Frame::PutCode => BOOT_OP::PUT_CODE,
+            Frame::GetCodeChunked { .. } => BOOT_OP::GET_CODE_CHUNKED,
+            Frame::PutChunk { .. } => BOOT_OP::PUT_CHUNK,
+            Frame::ChunkAck { .. } => BOOT_OP::CHUNK_ACK,
+            Frame::ChunkNak { .. } => BOOT_OP::CHUNK_NAK,
+            Frame::BootSuccess => BOOT_OP::BOOT_SUCCESS,
+            Frame::BootError { .. } => BOOT_OP::BOOT_ERROR,
+            Frame::PrintString { .. } => BOOT_OP::PRINT_STRING,
+            Frame::KernelExit { .. } => BOOT_OP::KERNEL_EXIT,
+            Frame::TestStart { .. } => BOOT_OP::TEST_START,
+            Frame::TestResult { .. } => BOOT_OP::TEST_RESULT,
+            Frame::ProfileSamples { .. } => BOOT_OP::PROFILE_SAMPLES,
+        }
+    }
+
+    /// Header length in bytes, including the op word.
+    pub const fn header_len(&self) -> usize {
+        match field_count(self.op()) {
+            Some(n) => 4 + 4 * n,
+            None => unreachable!(),
+        }
+    }
+
+    /// Length of the payload following the header, when the header says so.
+    ///
+    /// `PUT_CODE` returns `None`: its length comes from the earlier `PUT_PROG_INFO`.
+    pub const fn payload_len(&self) -> Option<usize> {
+        match self {
+            Frame::PutChunk { nbytes, .. } => Some(*nbytes as usize),
+            Frame::PrintString { len } => Some(*len as usize),
+            Frame::TestStart { len, .. } => Some(*len as usize),
+            Frame::ProfileSamples { count, .. } => {
+                Some((*count as usize).saturating_mul(PROFILE_SAMPLE_LEN))
+            }
+            Frame::PutCode => None,
+            _ => Some(0),
+        }
+    }
+
+    fn fields(&self) -> ([u32; 3], usize) {
+        match *self {
+            Frame::GetProgInfo | Frame::PutCode | Frame::BootSuccess => ([0; 3], 0),
+            Frame::GetCode { crc } => ([crc, 0, 0], 1),
+            Frame::ChunkNak { index } => ([index, 0, 0], 1),
+            Frame::PrintString { len } => ([len, 0, 0], 1),
+            Frame::KernelExit { code } => ([code, 0, 0], 1),
+            Frame::GetCodeChunked { crc, chunk_size } => ([crc, chunk_size, 0], 2),
+            Frame::ChunkAck { index, crc } => ([index, crc, 0], 2),
+            Frame::BootError { code, detail } => ([code, detail, 0], 2),
+            Frame::ProfileSamples { period_usec, count } => ([period_usec, count, 0], 2),
+            Frame::PutProgInfo { addr, nbytes, crc } => ([addr, nbytes, crc], 3),
+            Frame::PutChunk { index, nbytes, crc } => ([index, nbytes, crc], 3),
+            Frame::TestStart { index, total, len } => ([index, total, len], 3),
+            Frame::TestResult {
+                index,
+                status,
+                usec,
+            } => ([index, status, usec], 3),
+        }
+    }
+
+    /// Serializes the header. The payload, if any, is sent right after it.
+    pub fn encode(&self) -> Header {
+        let mut header = Header {
+            buf: [0; MAX_HEADER_LEN],
+            len: self.header_len(),
+        };
+        header.buf[..4].copy_from_slice(&self.op().val().to_le_bytes());
+
+        let (fields, n) = self.fields();
+        for (i, f) in fields[..n].iter().enumerate() {
+            header.buf[4 + i * 4..8 + i * 4].copy_from_slice(&f.to_le_bytes());
+        }
+        header
+    }
+
+    /// Parses the header at the start of `bytes`, returning it and its length.
+    pub fn decode(bytes: &[u8]) -> Result<(Frame, usize), DecodeError> {
+        if bytes.len() < 4 {
+            return Err(DecodeError::Truncated { needed: 4 });
+        }
+
+        let op_word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let op = BOOT_OP::from_u32(op_word).ok_or(DecodeError::UnknownOp(op_word))?;
+        let needed = match field_count(op) {
+            Some(n) => 4 + 4 * n,
+            None => return Err(DecodeError::UnexpectedOp(op)),
+        };
+        if bytes.len() < needed {
+            return Err(DecodeError::Truncated { needed });
+        }
+
+        let f = |i| field(bytes, i);
+        let frame = match op {
+            BOOT_OP::GET_PROG_INFO => Frame::GetProgInfo,
+            BOOT_OP::PUT_PROG_INFO => Frame::PutProgInfo {
+                addr: f(0),
+                nbytes: f(1),
+                crc: f(2),
+            },
+            BOOT_OP::GET_CODE => Frame::GetCode { crc: f(0) },
+            BOOT_OP::PUT_CODE => Frame::PutCode,
+            BOOT_OP::GET_CODE_CHUNKED => Frame::GetCodeChunked {
+                crc: f(0),
+                chunk_size: f(1),
+            },
+            BOOT_OP::PUT_CHUNK => Frame::PutChunk {
+                index: f(0),
+                nbytes: f(1),
+                crc: f(2),
+            },
+            BOOT_OP::CHUNK_ACK => Frame::ChunkAck {
+                index: f(0),
+                crc: f(1),
+            },
+            BOOT_OP::CHUNK_NAK => Frame::ChunkNak { index: f(0) },
+            BOOT_OP::BOOT_SUCCESS => Frame::BootSuccess,
+            BOOT_OP::BOOT_ERROR => Frame::BootError {
+                code: f(0),
+                detail: f(1),
+            },
+            BOOT_OP::PRINT_STRING => Frame::PrintString { len: f(0) },
+            BOOT_OP::KERNEL_EXIT => Frame::KernelExit { code: f(0) },
+            BOOT_OP::TEST_START => Frame::TestStart {
+                index: f(0),
+                total: f(1),
+                len: f(2),
+            },
+            BOOT_OP::TEST_RESULT => Frame::TestResult {
+                index: f(0),
+                status: f(1),
+                usec: f(2),
+            },
+            BOOT_OP::PROFILE_SAMPLES => Frame::ProfileSamples {
+                period_usec: f(0),
+                count: f(1),
+            },
+            BOOT_OP::BOOT_START => unreachable!(),
+        };
+
+        Ok((frame, needed))
+    }
+}
+
+/// An encoded frame header.
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    buf: [u8; MAX_HEADER_LEN],
+    len: usize,
+}
+
+impl AsRef<[u8]> for Header {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl core::ops::Deref for Header {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+/// Streams a whole frame, header then payload, one byte at a time.
+pub struct Encoder<'a> {
+    header: Header,
+    payload: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    /// Fails if `payload` does not have the length `frame` announces.
+    pub fn new(frame: Frame, payload: &'a [u8]) -> Result<Self, EncodeError> {
+        if let Some(expected) = frame.payload_len()
+            && expected != payload.len()
+        {
+            return Err(EncodeError::PayloadLength {
+                expected,
+                actual: payload.len(),
+            });
+        }
+
+        Ok(Self {
+            header: frame.encode(),
+            payload,
+            pos: 0,
+        })
+    }
+
+    /// Bytes left to send.
+    pub fn remaining(&self) -> usize {
+        self.header.len + self.payload.len() - self.pos
+    }
+}
+
+impl Iterator for Encoder<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let header_len = self.header.len;
+        let byte = if self.pos < header_len {
+            self.header.buf[self.pos]
+        } else {
+            *self.payload.get(self.pos - header_len)?
+        };
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining(), Some(self.remaining()))
+    }
+}
+
+impl ExactSizeIterator for Encoder<'_> {}
+
+/// Byte at a time header decoder for a stream that may drop or corrupt bytes.
+///
+/// When the first word is not a frame-starting `BOOT_OP`, the decoder reports it and slides
+/// forward by one byte, so it resyncs on the next op in the stream. Payloads are left to the
+/// caller: after a frame with a payload, read [`Frame::payload_len`] bytes before pushing again.
+#[derive(Clone, Debug)]
+pub struct Decoder {
+    buf: [u8; MAX_HEADER_LEN],
+    len: usize,
+    max_payload: u32,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(u32::MAX)
+    }
+}
+
+impl Decoder {
+    /// A decoder that rejects payloads longer than `max_payload` bytes.
+    pub const fn new(max_payload: u32) -> Self {
+        Self {
+            buf: [0; MAX_HEADER_LEN],
+            len: 0,
+            max_payload,
+        }
+    }
+
+    /// Drops any partial header.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// Bytes of the current partial header.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Feeds one byte. Returns a frame once its header is complete.
+    pub fn push(&mut self, byte: u8) -> Result<Option<Frame>, DecodeError> {
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        match Frame::decode(&self.buf[..self.len]) {
+            Ok((frame, _)) => {
+                self.len = 0;
+                // `PUT_CODE`'s length comes from `PUT_PROG_INFO`, which the caller checks.
+                let len = frame.payload_len().unwrap_or(0);
+                if len > self.max_payload as usize {
+                    return Err(DecodeError::PayloadTooLarge {
+                        len: u32::try_from(len).unwrap_or(u32::MAX),
+                        max: self.max_payload,
+                    });
+                }
+                Ok(Some(frame))
+            }
+            Err(DecodeError::Truncated { .. }) => Ok(None),
+            Err(e) => {
+                // Slide by one byte and look for an op in what is left.
+                self.buf.copy_within(1..self.len, 0);
+                self.len -= 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Feeds bytes until a frame is complete. Returns the frame and how many bytes were used;
+    /// bytes after the header are left alone (they may be its payload).
+    pub fn push_slice(&mut self, bytes: &[u8]) -> (Result<Option<Frame>, DecodeError>, usize) {
+        for (i, b) in bytes.iter().enumerate() {
+            match self.push(*b) {
+                Ok(None) => continue,
+                r => return (r, i + 1),
+            }
+        }
+        (Ok(None), bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_frames() -> [Frame; 15] {
+        [
+            Frame::GetProgInfo,
+            Frame::PutProgInfo {
+                addr: 0x8000,
+                nbytes: 1234,
+                crc: 0xdeadbeef,
+            },
+            Frame::GetCode { crc: 0xdeadbeef },
+            Frame::PutCode,
+            Frame::GetCodeChunked {
+                crc: 1,
+                chunk_size: 1024,
+            },
+            Frame::PutChunk {
+                index: 3,
+                nbytes: 4,
+                crc: 5,
+            },
+            Frame::ChunkAck { index: 3, crc: 5 },
+            Frame::ChunkNak { index: 7 },
+            Frame::BootSuccess,
+            Frame::BootError {
+                code: BOOT_ERR::CRC_MISMATCH.val(),
+                detail: 0xdeadbeef,
+            },
+            Frame::PrintString { len: 5 },
+            Frame::KernelExit { code: -1i32 as u32 },
+            Frame::TestStart {
+                index: 2,
+                total: 4,
+                len: 11,
+            },
+            Frame::TestResult {
+                index: 2,
+                status: TEST_STATUS::TIMEOUT.val(),
+                usec: 1_000_000,
+            },
+            Frame::ProfileSamples {
+                period_usec: 100,
+                count: 128,
+            },
+        ]
+    }
+
+    #[test]
+    fn header_layout_matches_the_old_hand_rolled_framing() {
+        let header = Frame::PutProgInfo {
+            addr: 0x8000,
+            nbytes: 0x10,
+            crc: 0xaabbccdd,
+        }
+        .encode();
+
+        assert_eq!(
+            &*header,
+            &[
+                0x44, 0x44, 0x33, 0x33, 0x00, 0x80, 0, 0, 0x10, 0, 0, 0, 0xdd, 0xcc, 0xbb, 0xaa
+            ]
+        );
+    }
+
+    #[test]
+    fn every_frame_round_trips() {
+        for frame in all_frames() {
+            let header = frame.encode();
+            assert_eq!(header.len(), frame.header_len());
+            assert_eq!(Frame::decode(&header), Ok((frame, header.len())));
+        }
+    }
+
+    #[test]
+    fn truncated_headers_ask_for_the_full_length() {
+        for frame in all_frames() {
+            let header = frame.encode();
+            for cut in 0..header.len() {
+                let needed = if cut < 4 { 4 } else { header.len() };
+                assert_eq!(
+                    Frame::decode(&header[..cut]),
+                    Err(DecodeError::Truncated { needed })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_and_unexpected_ops_are_rejected() {
+        assert_eq!(
+            Frame::decode(&0x12345678u32.to_le_bytes()),
+            Err(DecodeError::UnknownOp(0x12345678))
+        );
+        assert_eq!(
+            Frame::decode(&BOOT_OP::BOOT_START.val().to_le_bytes()),
+            Err(DecodeError::UnexpectedOp(BOOT_OP::BOOT_START))
+        );
+    }
+
+    #[test]
+    fn decoder_skips_a_dropped_byte() {
+        let mut decoder = Decoder::default();
+        let header = Frame::ChunkNak { index: 9 }.encode();
+
+        // The first op lost its first byte: the three left over are garbage.
+        let mut stream = [0u8; 3 + 8];
+        stream[..3].copy_from_slice(&Frame::GetProgInfo.encode()[1..]);
+        stream[3..].copy_from_slice(&header);
+
+        let mut frames = [None; 2];
+        let mut n = 0;
+        for b in stream {
+            if let Ok(Some(frame)) = decoder.push(b) {
+                frames[n] = Some(frame);
+                n += 1;
+            }
+        }
+        assert_eq!(frames, [Some(Frame::ChunkNak { index: 9 }), None]);
+    }
+
+    #[test]
+    fn decoder_limits_payloads() {
+        let mut decoder = Decoder::new(16);
+        let (result, used) = decoder.push_slice(&Frame::PrintString { len: 17 }.encode());
+        assert_eq!(
+            result,
+            Err(DecodeError::PayloadTooLarge { len: 17, max: 16 })
+        );
+        assert_eq!(used, 8);
+        assert!(decoder.pending().is_empty());
+    }
+
+    #[test]
+    fn encoder_checks_payload_length() {
+        let frame = Frame::PutChunk {
+            index: 0,
+            nbytes: 4,
+            crc: 0,
+        };
+        assert_eq!(
+            Encoder::new(frame, &[1, 2, 3]).err(),
+            Some(EncodeError::PayloadLength {
+                expected: 4,
+                actual: 3
+            })
+        );
+
+        let bytes = Encoder::new(frame, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(bytes.len(), 16 + 4);
+        assert!(bytes.skip(16).eq([1, 2, 3, 4]));
+
+        // PUT_CODE's length is whatever PUT_PROG_INFO said.
+        assert_eq!(Encoder::new(Frame::PutCode, &[9; 100]).unwrap().len(), 104);
+    }
+}
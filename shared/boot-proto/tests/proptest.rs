@@ -0,0 +1,184 @@
+use boot_proto::{BOOT_OP, DecodeError, Decoder, Encoder, Frame};
+use proptest::prelude::*;
+
+fn frame() -> impl Strategy<Value = Frame> {
+    let small = 0u32..4096;
+    prop_oneof![
+        Just(Frame::GetProgInfo),
+        (any::<u32>(), any::<u32>(), any::<u32>())
+            .prop_map(|(addr, nbytes, crc)| Frame::PutProgInfo { addr, nbytes, crc }),
+        any::<u32>().prop_map(|crc| Frame::GetCode { crc }),
+        Just(Frame::PutCode),
+        (any::<u32>(), any::<u32>())
+            .prop_map(|(crc, chunk_size)| Frame::GetCodeChunked { crc, chunk_size }),
+        (any::<u32>(), small.clone(), any::<u32>())
+            .prop_map(|(index, nbytes, crc)| Frame::PutChunk { index, nbytes, crc }),
+        (any::<u32>(), any::<u32>()).prop_map(|(index, crc)| Frame::ChunkAck { index, crc }),
+        any::<u32>().prop_map(|index| Frame::ChunkNak { index }),
+        Just(Frame::BootSuccess),
+        (any::<u32>(), any::<u32>()).prop_map(|(code, detail)| Frame::BootError { code, detail }),
+        small.clone().prop_map(|len| Frame::PrintString { len }),
+        any::<u32>().prop_map(|code| Frame::KernelExit { code }),
+        (any::<u32>(), any::<u32>(), small).prop_map(|(index, total, len)| Frame::TestStart {
+            index,
+            total,
+            len
+        }),
+        (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(index, status, usec)| {
+            Frame::TestResult {
+                index,
+                status,
+                usec,
+            }
+        }),
+        (any::<u32>(), 0u32..512)
+            .prop_map(|(period_usec, count)| Frame::ProfileSamples { period_usec, count }),
+    ]
+}
+
+/// Length of `PUT_CODE` payloads, which would come from `PUT_PROG_INFO`.
+const PUT_CODE_LEN: usize = 64;
+
+/// Frames with a payload of the announced length.
+fn frame_with_payload() -> impl Strategy<Value = (Frame, Vec<u8>)> {
+    frame().prop_flat_map(|frame| {
+        let len = frame.payload_len().unwrap_or(PUT_CODE_LEN);
+        (
+            Just(frame),
+            proptest::collection::vec(any::<u8>(), len..=len),
+        )
+    })
+}
+
+/// Decodes a whole stream, skipping payloads of frames that announce them.
+fn decode_stream(decoder: &mut Decoder, mut bytes: &[u8]) -> (Vec<Frame>, usize) {
+    let mut frames = Vec::new();
+    let mut errors = 0;
+    while !bytes.is_empty() {
+        let (result, used) = decoder.push_slice(bytes);
+        bytes = &bytes[used..];
+        match result {
+            Ok(Some(frame)) => {
+                let skip = frame.payload_len().unwrap_or(PUT_CODE_LEN).min(bytes.len());
+                bytes = &bytes[skip..];
+                frames.push(frame);
+            }
+            Ok(None) => {}
+            Err(_) => errors += 1,
+        }
+    }
+    (frames, errors)
+}
+
+/// True if some 4 byte window starting inside `garbage` reads as a `BOOT_OP`, i.e. the garbage
+/// could legitimately be mistaken for (the start of) a frame.
+fn has_op_window(garbage: &[u8], next: &[u8]) -> bool {
+    let joined = [garbage, next].concat();
+    (0..garbage.len()).any(|i| {
+        joined
+            .get(i..i + 4)
+            .is_some_and(|w| BOOT_OP::from_u32(u32::from_le_bytes(w.try_into().unwrap())).is_some())
+    })
+}
+
+proptest! {
+    #[test]
+    fn header_round_trips(frame in frame()) {
+        let header = frame.encode();
+        prop_assert_eq!(header.len(), frame.header_len());
+        prop_assert_eq!(Frame::decode(&header), Ok((frame, header.len())));
+    }
+
+    #[test]
+    fn encoder_and_decoder_agree((frame, payload) in frame_with_payload()) {
+        let bytes = Encoder::new(frame, &payload).unwrap().collect::<Vec<_>>();
+        let mut decoder = Decoder::default();
+
+        let (result, used) = decoder.push_slice(&bytes);
+        prop_assert_eq!(result, Ok(Some(frame)));
+        prop_assert_eq!(&bytes[used..], &payload[..]);
+    }
+
+    #[test]
+    fn every_truncation_is_reported(frame in frame(), extra in any::<u32>()) {
+        let header = frame.encode();
+        let cut = extra as usize % header.len();
+
+        let needed = if cut < 4 { 4 } else { header.len() };
+        prop_assert_eq!(Frame::decode(&header[..cut]), Err(DecodeError::Truncated { needed }));
+
+        // A streaming decoder just waits for more.
+        let mut decoder = Decoder::default();
+        prop_assert_eq!(decoder.push_slice(&header[..cut]), (Ok(None), cut));
+        prop_assert_eq!(decoder.pending().len(), cut);
+    }
+
+    #[test]
+    fn garbage_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = Frame::decode(&bytes);
+
+        let mut decoder = Decoder::new(1024);
+        for b in &bytes {
+            let _ = decoder.push(*b);
+            prop_assert!(decoder.pending().len() < boot_proto::MAX_HEADER_LEN);
+        }
+    }
+
+    #[test]
+    fn decoder_resyncs_after_garbage(
+        garbage in proptest::collection::vec(any::<u8>(), 0..64),
+        frames in proptest::collection::vec(frame_with_payload(), 1..8),
+    ) {
+        let stream = frames
+            .iter()
+            .flat_map(|(f, p)| Encoder::new(*f, p).unwrap())
+            .collect::<Vec<_>>();
+        prop_assume!(!has_op_window(&garbage, &stream));
+
+        let mut decoder = Decoder::default();
+        let (decoded, errors) = decode_stream(&mut decoder, &[&garbage[..], &stream].concat());
+
+        let expected = frames.iter().map(|(f, _)| *f).collect::<Vec<_>>();
+        prop_assert_eq!(decoded, expected);
+        prop_assert!(errors <= garbage.len());
+    }
+
+    #[test]
+    fn dropping_a_byte_loses_at_most_the_damaged_frames(
+        // Header-only frames: without payloads every byte belongs to some header.
+        frames in proptest::collection::vec(
+            frame().prop_filter("has a payload", |f| f.payload_len() == Some(0)),
+            2..8,
+        ),
+        drop_at in any::<prop::sample::Index>(),
+    ) {
+        let mut starts = Vec::new();
+        let mut stream = Vec::new();
+        for f in &frames {
+            starts.push(stream.len());
+            stream.extend_from_slice(&f.encode());
+        }
+        let dropped = drop_at.index(stream.len());
+        stream.remove(dropped);
+
+        // The frame that lost the byte may still take one from the next, but every op past
+        // that is where it was, less the dropped byte. Skip streams where the fields or the
+        // damage put an op anywhere else: the decoder would rightly take it for a frame.
+        let damaged = starts.iter().rposition(|&start| start <= dropped).unwrap();
+        let ops = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| if i <= damaged { start } else { start - 1 })
+            .collect::<Vec<_>>();
+        prop_assume!((0..stream.len()).all(|i| ops.contains(&i) || !has_op_window(&stream[i..i + 1], &stream[i + 1..])));
+
+        // Nothing has a payload, so a length read from damaged fields is refused rather than
+        // skipped over.
+        let mut decoder = Decoder::new(0);
+        let (decoded, _) = decode_stream(&mut decoder, &stream);
+        prop_assert!(decoded.len() <= frames.len());
+        prop_assert_eq!(&decoded[..damaged], &frames[..damaged]);
+        let after = frames.get(damaged + 2..).unwrap_or(&[]);
+        prop_assert!(decoded[damaged..].ends_with(after), "{:?} does not end with {:?}", decoded, after);
+    }
+}
@@ -6,6 +6,7 @@ use macros::enum_u32;
 pub const UART_BAUD_RATE: u32 = 921600;
 pub const SYSTEM_CLOCK_FREQUENCY: u32 = 250_000_000;
 pub const ARM_BASE: u32 = 0x8000;
+pub const BOOT_CHUNK_SIZE: u32 = 1024;
 
 enum_u32! {
     pub enum BOOT_OP {
@@ -20,8 +21,45 @@ enum_u32! {
         PUT_CODE        = 0x77778888,       // unix sends
 
         BOOT_SUCCESS    = 0x9999AAAA,       // pi sends on success
-        BOOT_ERROR      = 0xBBBBCCCC,       // pi sends on failure.
+        BOOT_ERROR      = 0xBBBBCCCC,       // pi sends [op, BOOT_ERR, detail] on failure.
 
         PRINT_STRING    = 0xDDDDEEEE,       // pi sends to print a string.
+
+        // chunked transfer: the pi asks for the code in acknowledged chunks
+        // instead of a single PUT_CODE burst.
+        GET_CODE_CHUNKED = 0x22223333,      // pi sends [op, cksum, chunk_size]
+        PUT_CHUNK       = 0x44445555,       // unix sends [op, index, nbytes, crc32, data]
+        CHUNK_ACK       = 0x66667777,       // pi sends [op, index, crc32]
+        CHUNK_NAK       = 0x88889999,       // pi sends [op, index] to request a resend
+
+        // after BOOT_SUCCESS only the kernel talks: text, then this when it is done.
+        KERNEL_EXIT     = 0xAAAABBBB,       // kernel sends [op, exit_code] before rebooting
+
+        // kernels built by `cargo test` frame each test's output with these.
+        TEST_START      = 0x8888AAAA,       // kernel sends [op, index, total, name_len, name]
+        TEST_RESULT     = 0x9999BBBB,       // kernel sends [op, index, TEST_STATUS, usec]
+
+        // crab_pi::profiler dumps its PC samples with this, in batches.
+        PROFILE_SAMPLES = 0xBBBB9999,       // kernel sends [op, period_usec, count, count * (addr, hits)]
+    }
+}
+
+enum_u32! {
+    // why the pi sent BOOT_ERROR; it then starts over with GET_PROG_INFO.
+    pub enum BOOT_ERR {
+        BAD_OP          = 1,                // detail: the op word we got instead
+        TOO_LARGE       = 2,                // detail: end of DRAM, from the mailbox
+        OVERLAP         = 3,                // detail: where the relocated bootloader starts
+        CRC_MISMATCH    = 4,                // detail: crc32 of what we received
+        TIMEOUT         = 5,                // detail: the op we were waiting for
+    }
+}
+
+enum_u32! {
+    // how a test run by a `cargo test` kernel ended.
+    pub enum TEST_STATUS {
+        PASS            = 0,
+        FAIL            = 1,                // it panicked
+        TIMEOUT         = 2,                // it ran past its timeout
     }
 }